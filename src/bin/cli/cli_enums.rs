@@ -5,6 +5,15 @@ use intruder::request_template::AttackType;
 pub enum HitType {
     Ok,
     All,
+    /// Any status but 404
+    Found,
+}
+
+/// Which response of a redirect chain the hit type is checked against
+#[derive(Copy, Clone, ValueEnum, Debug)]
+pub enum HitOn {
+    First,
+    Final,
 }
 
 #[derive(Copy, Clone, ValueEnum, Debug)]
@@ -21,13 +30,13 @@ pub enum AttackTypeCli{
     ClusterBomb
 }
 
-impl Into<AttackType> for AttackTypeCli {
-    fn into(self) -> AttackType {
-        match self {
-            Self::Sniper => AttackType::Sniper,
-            Self::BatteringRam => AttackType::BatteringRam,
-            Self::Pitchfork => AttackType::Pitchfork,
-            Self::ClusterBomb => AttackType::ClusterBomb,
+impl From<AttackTypeCli> for AttackType {
+    fn from(attack_type: AttackTypeCli) -> Self {
+        match attack_type {
+            AttackTypeCli::Sniper => AttackType::Sniper,
+            AttackTypeCli::BatteringRam => AttackType::BatteringRam,
+            AttackTypeCli::Pitchfork => AttackType::Pitchfork,
+            AttackTypeCli::ClusterBomb => AttackType::ClusterBomb,
         }
     }
 }
\ No newline at end of file
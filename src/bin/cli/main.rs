@@ -7,27 +7,126 @@
 //! defined payload positions.
 //!
 //! ```plaintext
-//! Usage: rip_intruder [OPTIONS] <REQ_F> <PASS_F>
+//! Usage: rip_intruder [OPTIONS] <REQ_F> [PASS_F]...
 //!
 //! Arguments:
-//!   <REQ_F>   Path to request template file
-//!   <PASS_F>  Path to password file
+//!   <REQ_F>
+//!           Path to request template file
+//!
+//!   [PASS_F]...
+//!           Paths to password files, read one after the other, - reads from stdin
 //!
 //! Options:
+//!       --payloads <PAYLOADS>
+//!           Generate payloads, after the ones from the password files (e.g. numbers:0000-9999)
+//!
+//!       --dedupe [<MIB>]
+//!           Remove duplicate payloads, using a filter of the given size in MiB. The filter also skips a few unique payloads, about 1 in 50000 with 4 bytes per payload (e.g. 64 MiB for 16M payloads), 1 in 25 with half a byte
+//!
 //!   -c, --concurrent-requests <CONCURRENT_REQUESTS>
-//!           Number of concurrent requests [default: 1]
+//!           Number of concurrent requests
+//!
+//!           [default: 1]
+//!
 //!   -p, --pattern <PATTERN>
-//!           Regex pattern [default: §§]
+//!           Regex pattern
+//!
+//!           [default: §§]
+//!
 //!       --hit-type <HIT_TYPE>
-//!           What is considered a hit [default: ok] [possible values: ok, all]
-//!   -o <OF>
+//!           What is considered a hit [default: ok, all with --vhost, found with --discover]
+//!
+//!           Possible values:
+//!           - ok
+//!           - all
+//!           - found: Any status but 404
+//!
+//!   -o <OUT_FILE>
 //!           Output to file
+//!
 //!   -s <STOP>
-//!           Stop after n hits, -1 to try all provided words [default: 1]
-//!       --format <OUT_FORMAT>
-//!           Output format [default: csv] [possible values: csv, jsonl]
+//!           Stop after n hits, -1 to try all provided words
+//!
+//!           [default: 1]
+//!
+//!   -f, --format <OUT_FORMAT>
+//!           Output format
+//!
+//!           [default: csv]
+//!           [possible values: csv, jsonl]
+//!
+//!       --prog
+//!           Progress Bar
+//!
+//!   -a, --attack-type <ATTACK_TYPE>
+//!           Attack type
+//!
+//!           [default: battering-ram]
+//!           [possible values: sniper, battering-ram, pitchfork, cluster-bomb]
+//!
+//!   -r, --rules <RULES>
+//!           Mangle every payload with the rules in a hashcat-compatible rule file
+//!
+//!       --recursive-grep <RECURSIVE_GREP>
+//!           Extract every next payload from the previous response with this regex, one request at a time
+//!
+//!       --initial-payload <INITIAL_PAYLOAD>
+//!           First payload sent with --recursive-grep
+//!
+//!           [default: ]
+//!
+//!       --race
+//!           Release the requests together in batches of -c requests, using last-byte sync
+//!
+//!       --raw
+//!           Send the template bytes verbatim over a raw socket, to --target or the host from the request line or Host header
+//!
+//!       --fix-length
+//!           Recompute the Content-Length header in raw mode
+//!
+//!       --tls
+//!           Connect to the target with TLS
+//!
+//!       --target <TARGET>
+//!           Connect to this target (e.g. https://10.0.0.1:8443) instead of the host from the template, the Host header is sent unchanged
+//!
+//!       --vhost [<DOMAIN>]
+//!           Virtual host discovery: payloads are sent as the Host header (as <payload>.<DOMAIN> if given), only responses differing from a baseline are reported
+//!
+//!       --discover <BASE_URL>
+//!           Content discovery: payloads are appended to the path of BASE_URL, recursing into the directories found. Method and headers come from the template
+//!
+//!   -x, --extensions <EXTENSIONS>
+//!           Extensions appended to every payload in discovery mode (e.g. php,bak)
+//!
+//!       --max-depth <MAX_DEPTH>
+//!           How many directory levels below BASE_URL to recurse into
+//!
+//!           [default: 3]
+//!
+//!       --follow-redirects [<MAX>]
+//!           Follow up to MAX redirects per request, carrying over the cookies they set
+//!
+//!       --same-host
+//!           Only follow redirects to the host the request was sent to
+//!
+//!       --hit-on <HIT_ON>
+//!           Which response of a redirect chain the hit type is checked against
+//!
+//!           [default: final]
+//!           [possible values: first, final]
+//!
+//!       --no-decompress
+//!           Keep the response bodies as they were sent, without decoding their Content-Encoding
+//!
+//!       --max-body-size <KIB>
+//!           Read at most this many KiB of every response body, the rest is cut off and flagged in the output, 0 for no limit
+//!
+//!           [default: 10240]
+//!
 //!   -h, --help
-//!           Print help information
+//!           Print help information (use `-h` for a summary)
+//!
 //!   -V, --version
 //!           Print version information
 //! ```
@@ -36,11 +135,14 @@ mod output;
 
 use cli_enums::AttackTypeCli;
 use intruder::intruder::Intruder;
-use intruder::intruder::IntruderConfig;
+use intruder::intruder::{Discovery, IntruderConfig, RecursiveGrep, Redirects, VirtualHosts};
+use intruder::payloads::{PayloadGenerator, PayloadSource, Stdin, Wordlist};
 use output::Cli;
-use cli_enums::{HitType, OutputFormat};
+use cli_enums::{HitOn, HitType, OutputFormat};
 use anyhow::Result;
 use clap::Parser;
+use hyper::Uri;
+use regex::bytes::Regex;
 use output::CliConfig;
 use std::io::stderr;
 use std::io::Write;
@@ -53,9 +155,17 @@ pub struct Args {
     #[arg(index = 1, value_hint = clap::ValueHint::FilePath)]
     req_f: std::path::PathBuf,
 
-    /// Path to password file
-    #[arg(index = 2, value_hint = clap::ValueHint::FilePath)]
-    pass_f: std::path::PathBuf,
+    /// Paths to password files, read one after the other, - reads from stdin
+    #[arg(index = 2, value_hint = clap::ValueHint::FilePath, required_unless_present_any = ["payloads", "recursive_grep"])]
+    pass_f: Vec<std::path::PathBuf>,
+
+    /// Generate payloads, after the ones from the password files (e.g. numbers:0000-9999)
+    #[arg(long)]
+    payloads: Vec<PayloadGenerator>,
+
+    /// Remove duplicate payloads, using a filter of the given size in MiB. The filter also skips a few unique payloads, about 1 in 50000 with 4 bytes per payload (e.g. 64 MiB for 16M payloads), 1 in 25 with half a byte
+    #[arg(long, value_name = "MIB", num_args = 0..=1, default_missing_value = "64", value_parser = |size: &str| bytes(size, 1 << 20))]
+    dedupe: Option<usize>,
 
     /// Number of concurrent requests
     #[arg(short, long, default_value_t = 1)]
@@ -65,9 +175,9 @@ pub struct Args {
     #[arg(short, long, default_value_t = str::to_string("§§"))]
     pattern: String,
 
-    /// What is considered a hit
-    #[arg(long, value_enum, default_value_t = HitType::Ok)]
-    hit_type: HitType,
+    /// What is considered a hit [default: ok, all with --vhost, found with --discover]
+    #[arg(long, value_enum)]
+    hit_type: Option<HitType>,
 
     /// Output to file
     #[arg(short)]
@@ -87,24 +197,151 @@ pub struct Args {
 
     /// Attack type
     #[arg(short, long, value_enum, default_value_t = AttackTypeCli::BatteringRam)]
-    attack_type: AttackTypeCli
+    attack_type: AttackTypeCli,
+
+    /// Mangle every payload with the rules in a hashcat-compatible rule file
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    rules: Option<PathBuf>,
+
+    /// Extract every next payload from the previous response with this regex, one request at a time
+    #[arg(long, conflicts_with_all = ["pass_f", "payloads"])]
+    recursive_grep: Option<Regex>,
+
+    /// First payload sent with --recursive-grep
+    #[arg(long, default_value_t = String::new(), requires = "recursive_grep")]
+    initial_payload: String,
+
+    /// Release the requests together in batches of -c requests, using last-byte sync
+    #[arg(long, conflicts_with = "recursive_grep")]
+    race: bool,
+
+    /// Send the template bytes verbatim over a raw socket, to --target or the host from the request line or Host header
+    #[arg(long, conflicts_with_all = ["recursive_grep", "race"])]
+    raw: bool,
+
+    /// Recompute the Content-Length header in raw mode
+    #[arg(long, requires = "raw")]
+    fix_length: bool,
+
+    /// Connect to the target with TLS
+    #[arg(long)]
+    tls: bool,
+
+    /// Connect to this target (e.g. https://10.0.0.1:8443) instead of the host from the template, the Host header is sent unchanged
+    #[arg(long)]
+    target: Option<Uri>,
+
+    /// Virtual host discovery: payloads are sent as the Host header (as <payload>.<DOMAIN> if given), only responses differing from a baseline are reported
+    #[arg(long, value_name = "DOMAIN", num_args = 0..=1, default_missing_value = "", conflicts_with_all = ["recursive_grep", "race", "raw"])]
+    vhost: Option<String>,
+
+    /// Content discovery: payloads are appended to the path of BASE_URL, recursing into the directories found. Method and headers come from the template
+    #[arg(long, value_name = "BASE_URL", conflicts_with_all = ["recursive_grep", "race", "raw", "vhost", "target"])]
+    discover: Option<Uri>,
+
+    /// Extensions appended to every payload in discovery mode (e.g. php,bak)
+    #[arg(short = 'x', long, value_delimiter = ',', requires = "discover")]
+    extensions: Vec<String>,
+
+    /// How many directory levels below BASE_URL to recurse into
+    #[arg(long, default_value_t = 3, requires = "discover")]
+    max_depth: usize,
+
+    /// Follow up to MAX redirects per request, carrying over the cookies they set
+    #[arg(long, value_name = "MAX", num_args = 0..=1, default_missing_value = "10", conflicts_with_all = ["race", "raw", "discover"])]
+    follow_redirects: Option<usize>,
+
+    /// Only follow redirects to the host the request was sent to
+    #[arg(long, requires = "follow_redirects")]
+    same_host: bool,
+
+    /// Which response of a redirect chain the hit type is checked against
+    #[arg(long, value_enum, default_value_t = HitOn::Final, requires = "follow_redirects")]
+    hit_on: HitOn,
+
+    /// Keep the response bodies as they were sent, without decoding their Content-Encoding
+    #[arg(long)]
+    no_decompress: bool,
+
+    /// Read at most this many KiB of every response body, the rest is cut off and flagged in the output, 0 for no limit
+    #[arg(long, value_name = "KIB", default_value = "10240", value_parser = |size: &str| bytes(size, 1024))]
+    max_body_size: usize,
+}
+
+/// Parses a size given in `unit`s into bytes
+fn bytes(size: &str, unit: usize) -> Result<usize, String> {
+    size.parse::<usize>()
+        .map_err(|err| err.to_string())?
+        .checked_mul(unit)
+        .ok_or("size too large".to_string())
 }
 
 fn get_configs(args: Args) -> (CliConfig, IntruderConfig){
     let cliconfig = CliConfig{
         out_format: args.out_format,
         out_file: args.out_file,
-        hit_type: args.hit_type,
+        hit_type: args.hit_type.unwrap_or(if args.vhost.is_some() {
+            HitType::All
+        } else if args.discover.is_some() {
+            HitType::Found
+        } else {
+            HitType::Ok
+        }),
+        hit_on: args.hit_on,
         stop: args.stop,
         progress_bar: args.progress_bar
     };
 
     let intruderconfig = IntruderConfig {
         req_f: args.req_f,
-        pass_f: args.pass_f,
+        payloads: args
+            .pass_f
+            .into_iter()
+            .map(|path| -> Box<dyn PayloadSource> {
+                if path.as_os_str() == "-" {
+                    Box::new(Stdin)
+                } else {
+                    Box::new(Wordlist::new(path))
+                }
+            })
+            .chain(
+                args.payloads
+                    .into_iter()
+                    .map(|generator| Box::new(generator) as Box<dyn PayloadSource>),
+            )
+            .collect(),
+        dedupe: args.dedupe,
+        rules_f: args.rules,
         pattern: args.pattern,
         concurrent_requests: args.concurrent_requests,
-        attack_type: args.attack_type.into()
+        attack_type: args.attack_type.into(),
+        recursive_grep: args.recursive_grep.map(|extract| RecursiveGrep {
+            extract,
+            initial: args.initial_payload.into_bytes(),
+        }),
+        race: args.race,
+        raw: args.raw,
+        fix_length: args.fix_length,
+        tls: args.tls,
+        target: args.target,
+        vhost: args.vhost.map(|domain| VirtualHosts {
+            domain: (!domain.is_empty()).then_some(domain),
+        }),
+        discover: args.discover.map(|base| Discovery {
+            base,
+            extensions: args
+                .extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_string())
+                .collect(),
+            max_depth: args.max_depth,
+        }),
+        redirects: args.follow_redirects.map(|max| Redirects {
+            max,
+            same_host: args.same_host,
+        }),
+        decompress: !args.no_decompress,
+        max_body_size: (args.max_body_size > 0).then_some(args.max_body_size),
     };
 
     (cliconfig, intruderconfig)
@@ -118,7 +355,7 @@ async fn main() -> Result<()> {
     let cli = Cli::new(cliconfig);
     let intruder = Intruder::new(intruderconfig)?;
     let errors = cli.run(intruder).await?;
-    if errors.len() > 0 {
+    if !errors.is_empty() {
         writeln!(
             stderr(),
             "These payloads were not sent successfully: {:?}",
@@ -1,25 +1,32 @@
 use anyhow::Result;
 
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
 use hyper::body;
 use hyper::{Body, Response, StatusCode};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
-use intruder::intruder::Intruder;
+use intruder::encoding::Truncated;
+use intruder::intruder::{Hop, Intruder, RedirectChain};
 use serde_json::{json, Value};
 
 use futures::StreamExt;
+use std::cell::RefCell;
 use std::fmt::Display;
 use std::fs::OpenOptions;
 use std::io::{prelude::*, stdout};
 use std::path::PathBuf;
+use std::rc::Rc;
 
-use crate::cli_enums::{HitType, OutputFormat};
+use crate::cli_enums::{HitOn, HitType, OutputFormat};
 
 pub struct CliConfig {
     pub out_format: OutputFormat,
     pub out_file: Option<PathBuf>,
     pub hit_type: HitType,
+    pub hit_on: HitOn,
     pub stop: isize,
     pub progress_bar: bool,
 }
@@ -27,17 +34,23 @@ pub struct CliConfig {
 /// Struct for detecting hits
 struct Hit {
     hit_type: HitType,
+    hit_on: HitOn,
 }
 
 impl Hit {
-    fn new(hit_type: HitType) -> Self {
-        Self { hit_type }
+    fn new(hit_type: HitType, hit_on: HitOn) -> Self {
+        Self { hit_type, hit_on }
     }
 
     fn is_hit(&self, resp: &Response<Body>) -> bool {
+        let status = match (self.hit_on, resp.extensions().get::<RedirectChain>()) {
+            (HitOn::First, Some(chain)) => chain.first_status(resp.status()),
+            _ => resp.status(),
+        };
         match self.hit_type {
-            HitType::Ok => Hit::success_hit(&resp),
+            HitType::Ok => Hit::success_hit(status),
             HitType::All => Hit::all_hit(),
+            HitType::Found => Hit::found_hit(status),
         }
     }
 
@@ -45,20 +58,24 @@ impl Hit {
         true
     }
 
-    fn success_hit(resp: &Response<Body>) -> bool {
-        if resp.status() == 200 {
-            true
-        } else {
-            false
-        }
+    fn success_hit(status: StatusCode) -> bool {
+        status == 200
+    }
+
+    fn found_hit(status: StatusCode) -> bool {
+        status != 404
     }
 }
 
 /// Represents one line of the output
 pub(crate) struct OutLine {
     status: StatusCode,
+    /// Redirects followed to get the response, None when not following them
+    redirects: Option<Vec<Hop>>,
+    /// Whether the body was cut off at the size limit
+    truncated: bool,
     body: Body,
-    payload: String,
+    payload: Vec<u8>,
     idx: usize,
 }
 
@@ -83,9 +100,14 @@ impl Display for Out {
 }
 
 impl OutLine {
-    pub(crate) async fn new(resp: Response<Body>, payload: String, idx: usize) -> Result<Self> {
+    pub(crate) async fn new(resp: Response<Body>, payload: Vec<u8>, idx: usize) -> Result<Self> {
         Ok(Self {
             status: resp.status(),
+            redirects: resp
+                .extensions()
+                .get::<RedirectChain>()
+                .map(|chain| chain.0.clone()),
+            truncated: resp.extensions().get::<Truncated>().is_some(),
             body: resp.into_body(),
             payload,
             idx,
@@ -93,11 +115,12 @@ impl OutLine {
     }
 
     fn output_bar(out: Out, bar: &ProgressBar) -> Result<()> {
-        Ok(bar.println(out.to_string()))
+        bar.println(out.to_string());
+        Ok(())
     }
 
     /// Writes output, consuming self in the process.
-    pub(crate) async fn output<'a>(
+    pub(crate) async fn output(
         self,
         config: &CliConfig,
         writer: &mut Writer<'_>,
@@ -111,23 +134,69 @@ impl OutLine {
 
     async fn create_output(self, config: &CliConfig) -> Result<Out> {
         match config.out_format {
-            OutputFormat::Csv => Ok(Out::Msg(format!(
-                "{:}, {:}, {:}",
-                self.idx, self.payload, self.status
-            ))),
+            OutputFormat::Csv => {
+                let mut line = format!(
+                    "{:}, {:}, {:}",
+                    self.idx,
+                    String::from_utf8_lossy(&self.payload),
+                    self.status
+                );
+                // Kept free of commas, the status has to stay the last column
+                let hops = self.redirects.iter().flatten().map(|hop| {
+                    format!(
+                        "{} -> {}",
+                        hop.status.as_u16(),
+                        String::from_utf8_lossy(hop.location.as_bytes())
+                    )
+                });
+                let hops = hops.collect::<Vec<_>>().join("; ");
+                if !hops.is_empty() {
+                    line = format!("{} ({})", line, hops);
+                }
+                if self.truncated {
+                    line.push_str(" [truncated]");
+                }
+                Ok(Out::Msg(line))
+            }
             OutputFormat::Jsonl => {
-                let body = String::from_utf8(body::to_bytes(self.body).await?.to_vec())?;
-                let out = json!({
-                    "Payload": self.payload,
+                let body = body::to_bytes(self.body).await?.to_vec();
+                let mut out = json!({
                     "Status": self.status.as_u16(),
-                    "Body": body
                     }
                 );
+                Self::insert_bytes(&mut out, "Payload", self.payload);
+                Self::insert_bytes(&mut out, "Body", body);
+                if self.truncated {
+                    out["Truncated"] = Value::Bool(true);
+                }
+                if let Some(redirects) = self.redirects {
+                    out["Redirects"] = redirects
+                        .iter()
+                        .map(|hop| {
+                            json!({
+                                "Status": hop.status.as_u16(),
+                                "Location": String::from_utf8_lossy(hop.location.as_bytes()),
+                            })
+                        })
+                        .collect();
+                }
                 Ok(Out::Json(out))
             }
         }
     }
 
+    /// Inserts bytes into a JSON object, as a string if they are valid UTF-8 and in base64
+    /// otherwise, flagged by a `<key>Encoding` key.
+    fn insert_bytes(out: &mut Value, key: &str, bytes: Vec<u8>) {
+        match String::from_utf8(bytes) {
+            Ok(text) => out[key] = Value::String(text),
+            Err(err) => {
+                out[key] = Value::String(STANDARD.encode(err.into_bytes()));
+                out[format!("{}Encoding", key)] = Value::from("base64");
+            }
+        }
+    }
+
     async fn output_file(out: Out, writer: &mut Box<dyn Write>) -> Result<()> {
         writeln!(writer, "{:}", out)?;
         Ok(())
@@ -152,12 +221,48 @@ impl Cli {
 
         Self {
             bar,
-            hit_d: Hit::new(config.hit_type),
+            hit_d: Hit::new(config.hit_type, config.hit_on),
             config,
         }
     }
 
     pub async fn run(&self, intr: Intruder) -> Result<Vec<String>> {
+        if let Some(bar) = &self.bar {
+            match intr.payload_count()? {
+                Some(bar_len) => bar.set_length(bar_len as u64),
+                // The total is unknown when reading from stdin, so only show what was sent
+                None => bar.set_style(
+                    ProgressStyle::with_template(
+                        "{msg} {spinner}\n[{elapsed_precise}] {pos}\nReq/sec: {per_sec}",
+                    )
+                    .unwrap(),
+                ),
+            }
+        }
+
+        // The responses are set up before the output file is opened, so it isn't truncated if the
+        // payload sources fail. Recursive grep extracts its payloads from the responses instead.
+        // The payloads end at the first one that can't be read, and the run fails with its error
+        // once the responses sent before it are written.
+        let read_error = Rc::new(RefCell::new(None));
+        let config = &intr.config;
+        let mut responses = match &config.recursive_grep {
+            Some(grep) => intr.recursive_grep(grep).boxed_local(),
+            None => {
+                let read_error = read_error.clone();
+                let payloads = intr.get_payload_buffer()?.map_while(move |payload| {
+                    payload.map_err(|err| *read_error.borrow_mut() = Some(err)).ok()
+                });
+                match (&config.vhost, &config.discover) {
+                    (Some(vhosts), _) => intr.vhost(payloads, vhosts).await?.boxed_local(),
+                    (_, Some(discovery)) => intr.discover(payloads, discovery).boxed_local(),
+                    _ if config.raw => intr.raw(payloads).await?.boxed_local(),
+                    _ if config.race => intr.race(payloads).await?.boxed_local(),
+                    _ => intr.bruteforce(payloads).await?.boxed_local(),
+                }
+            }
+        };
+
         let mut writer = match &self.config.out_file {
             Some(path) => Writer::File(Box::new(
                 OpenOptions::new()
@@ -175,18 +280,10 @@ impl Cli {
             }
         };
 
-        let payloads = intr.get_payload_buffer();
-
-        if let Some(bar) = &self.bar {
-            let bar_len = intr.get_payload_buffer().count();
-            bar.set_length(bar_len as u64);
-        }
-
-        let mut responses = intr.bruteforce(payloads).await?;
         let mut hits = 0;
         let mut errors = vec![];
 
-        while let Some(resp_pay) = responses.next().await {
+        'responses: while let Some(resp_pay) = responses.next().await {
             let (responses, payload);
             match resp_pay {
                 Ok(result) => {
@@ -210,10 +307,140 @@ impl Cli {
                         .await?;
                 }
                 if hits as isize == self.config.stop {
-                    break;
+                    break 'responses;
                 }
             }
         }
+        if let Some(err) = read_error.take() {
+            return Err(err);
+        }
         Ok(errors)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    use intruder::payloads::{PayloadGenerator, PayloadSource};
+
+    fn line(idx: usize, payload: &[u8], status: u16, body: &[u8]) -> OutLine {
+        OutLine {
+            status: StatusCode::from_u16(status).unwrap(),
+            redirects: None,
+            truncated: false,
+            body: Body::from(body.to_vec()),
+            payload: payload.to_vec(),
+            idx,
+        }
+    }
+
+    fn hop(status: u16, location: &[u8]) -> Hop {
+        Hop {
+            status: StatusCode::from_u16(status).unwrap(),
+            location: HeaderValue::from_bytes(location).unwrap(),
+        }
+    }
+
+    /// Writes the lines as a run would, then reads them back with the `results` generator
+    async fn round_trip(
+        name: &str,
+        out_format: OutputFormat,
+        lines: Vec<OutLine>,
+        opts: &[&str],
+    ) -> Vec<Result<Vec<Vec<u8>>>> {
+        let name = format!("rip-intruder-{}-{}", std::process::id(), name);
+        let path = std::env::temp_dir().join(name);
+        let config = CliConfig {
+            out_format,
+            out_file: Some(path.clone()),
+            hit_type: HitType::All,
+            hit_on: HitOn::Final,
+            stop: -1,
+            progress_bar: false,
+        };
+        let mut writer = Writer::File(Box::new(std::fs::File::create(&path).unwrap()));
+        for line in lines {
+            line.output(&config, &mut writer).await.unwrap();
+        }
+        drop(writer);
+
+        let read = opts
+            .iter()
+            .map(|opts| {
+                let spec = format!("results:{}{}", path.display(), opts);
+                let results: PayloadGenerator = spec.parse().unwrap();
+                results.payloads().unwrap().collect()
+            })
+            .collect();
+        std::fs::remove_file(path).unwrap();
+        read
+    }
+
+    fn strings(payloads: &Result<Vec<Vec<u8>>>) -> Vec<String> {
+        let payloads = payloads.as_ref().unwrap();
+        payloads.iter().map(|p| String::from_utf8_lossy(p).into_owned()).collect()
+    }
+
+    #[tokio::test]
+    async fn csv_output_reads_back() {
+        let mut redirected = line(2, b"admin", 200, b"");
+        redirected.redirects = Some(vec![hop(302, b"/login?next=/a"), hop(301, b"/b")]);
+        redirected.truncated = true;
+        let mut truncated = line(3, b"x", 500, b"");
+        truncated.truncated = true;
+        let lines = vec![line(1, b"a, b, 200 OK", 403, b""), redirected, truncated];
+
+        let opts = ["", ",field=Index", ",field=Status", ",status=200+500", ",status=302"];
+        let read = round_trip("out.csv", OutputFormat::Csv, lines, &opts).await;
+        assert_eq!(strings(&read[0]), ["a, b, 200 OK", "admin", "x"]);
+        assert_eq!(strings(&read[1]), ["1", "2", "3"]);
+        assert_eq!(strings(&read[2]), ["403", "200", "500"]);
+        assert_eq!(strings(&read[3]), ["admin", "x"]);
+        // Filtered on the final status, not the ones of the redirects
+        assert!(strings(&read[4]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn jsonl_output_reads_back() {
+        let mut redirected = line(2, b"\xff\xfe, x", 200, b"\x00\xffbinary");
+        redirected.redirects = Some(vec![hop(302, b"/login?next=/a")]);
+        let lines = vec![line(1, b"caf\xc3\xa9", 404, b"not found"), redirected];
+
+        let opts = [
+            "",
+            ",field=Body",
+            ",field=/Body",
+            ",field=Status",
+            ",field=/Redirects/0/Location",
+            ",status=200",
+        ];
+        let read = round_trip("out.jsonl", OutputFormat::Jsonl, lines, &opts).await;
+        let payloads = read[0].as_ref().unwrap();
+        assert_eq!(payloads, &[b"caf\xc3\xa9".to_vec(), b"\xff\xfe, x".to_vec()]);
+        let bodies = read[1].as_ref().unwrap();
+        assert_eq!(bodies, &[b"not found".to_vec(), b"\x00\xffbinary".to_vec()]);
+        assert_eq!(read[2].as_ref().unwrap(), bodies);
+        assert_eq!(strings(&read[3]), ["404", "200"]);
+        assert_eq!(strings(&read[4]), ["/login?next=/a"]);
+        assert_eq!(read[5].as_ref().unwrap(), &[b"\xff\xfe, x".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn fields_missing_from_every_line_are_errors() {
+        let lines = || vec![line(1, b"admin", 200, b"welcome")];
+        let read = round_trip("typo.csv", OutputFormat::Csv, lines(), &[",field=Body"]).await;
+        let err = read[0].as_ref().unwrap_err().to_string();
+        assert!(err.starts_with("No line of "), "{}", err);
+        assert!(err.ends_with("has the field Body"), "{}", err);
+
+        let opts = [",field=Paylod", ",field=/Redirects/0/Location", ",status=500"];
+        let read = round_trip("typo.jsonl", OutputFormat::Jsonl, lines(), &opts).await;
+        assert!(read[0].is_err());
+        assert!(read[1].is_err());
+        // The field is there, it's just filtered out
+        assert!(read[2].as_ref().unwrap().is_empty());
+    }
+}
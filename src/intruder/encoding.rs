@@ -0,0 +1,262 @@
+//! Encoding
+//!
+//! This module houses the reading of response bodies, capped at a size limit, and their decoding
+//! according to their `Content-Encoding`, so that they can be matched against and written out as
+//! the server meant them.
+use anyhow::{anyhow, Result};
+
+use brotli_decompressor::Decompressor;
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::{Body, Response};
+
+use std::io::Read;
+
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Marks a response whose body was cut off at the size limit
+#[derive(Clone, Copy, Debug)]
+pub struct Truncated;
+
+/// Reads a body, up to `limit` bytes if given, returning whether it was cut off. The rest of a
+/// body that was cut off is left unread.
+pub async fn read_body(body: &mut Body, limit: Option<usize>) -> Result<(Vec<u8>, bool)> {
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        match limit {
+            Some(limit) if bytes.len() + chunk.len() > limit => {
+                bytes.extend_from_slice(&chunk[..limit - bytes.len()]);
+                return Ok((bytes, true));
+            }
+            _ => bytes.extend_from_slice(&chunk),
+        }
+    }
+    Ok((bytes, false))
+}
+
+/// Reverses a single content coding, reading at most `limit` bytes of output.
+fn decode_one(coding: &str, bytes: &[u8], limit: u64) -> Result<Vec<u8>> {
+    let mut decoded = vec![];
+    match coding {
+        "gzip" | "x-gzip" => {
+            MultiGzDecoder::new(bytes).take(limit).read_to_end(&mut decoded)?;
+        }
+        "deflate" => {
+            // Meant to be zlib wrapped, but some servers send raw deflate streams
+            if ZlibDecoder::new(bytes).take(limit).read_to_end(&mut decoded).is_err() {
+                decoded.clear();
+                DeflateDecoder::new(bytes).take(limit).read_to_end(&mut decoded)?;
+            }
+        }
+        "br" => {
+            Decompressor::new(bytes, 4096).take(limit).read_to_end(&mut decoded)?;
+        }
+        "zstd" => {
+            ZstdDecoder::new(bytes)?.take(limit).read_to_end(&mut decoded)?;
+        }
+        "identity" => decoded.extend_from_slice(bytes),
+        _ => return Err(anyhow!("Unsupported content coding {}", coding)),
+    }
+    Ok(decoded)
+}
+
+/// Reads the body of a response into memory, then decodes it according to its
+/// `Content-Encoding` if `decompress` is set.
+///
+/// Bodies are read up to `limit` bytes, and the responses cut off there get a [Truncated]
+/// extension. Those are not decoded, as their body is incomplete, while decoded bodies are cut
+/// off at the limit in turn. The codings are reversed in the opposite order of the one they were
+/// applied in, then the `Content-Encoding` and `Content-Length` headers are removed. Responses
+/// using a coding that isn't supported, or failing to decode, keep their body as it was sent.
+pub async fn buffer(
+    resp: Response<Body>,
+    limit: Option<usize>,
+    decompress: bool,
+) -> Result<Response<Body>> {
+    let (mut parts, mut body) = resp.into_parts();
+    let (mut bytes, mut truncated) = read_body(&mut body, limit).await?;
+    truncated |= parts.extensions.get::<Truncated>().is_some();
+
+    let codings: Vec<String> = parts
+        .headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if decompress && !truncated && !codings.is_empty() {
+        // One more byte than the limit, to tell whether the output was cut off
+        let max = limit.map_or(u64::MAX, |limit| limit as u64 + 1);
+        let decoded = codings
+            .iter()
+            .rev()
+            .try_fold(bytes.clone(), |bytes, coding| decode_one(coding, &bytes, max));
+        if let Ok(mut decoded) = decoded {
+            if let Some(limit) = limit.filter(|&limit| decoded.len() > limit) {
+                decoded.truncate(limit);
+                truncated = true;
+            }
+            parts.headers.remove(CONTENT_ENCODING);
+            parts.headers.remove(CONTENT_LENGTH);
+            bytes = decoded;
+        }
+    }
+
+    if truncated {
+        parts.extensions.insert(Truncated);
+    }
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use hyper::body;
+    use hyper::header::HeaderValue;
+
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"<html>Welcome back, admin</html>";
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zlib(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A body handed out in the given chunks
+    fn chunked(chunks: &[&'static [u8]]) -> Body {
+        let chunks = chunks.iter().map(|&chunk| Ok::<_, std::io::Error>(chunk));
+        Body::wrap_stream(futures::stream::iter(chunks.collect::<Vec<_>>()))
+    }
+
+    /// Buffers a response with the given `Content-Encoding` values, returning it with its body
+    async fn decoded(
+        codings: &[&'static str],
+        bytes: Vec<u8>,
+        limit: Option<usize>,
+    ) -> (Response<()>, Vec<u8>) {
+        let mut resp = Response::new(Body::from(bytes.clone()));
+        for &coding in codings {
+            resp.headers_mut().append(CONTENT_ENCODING, HeaderValue::from_static(coding));
+        }
+        resp.headers_mut().insert(CONTENT_LENGTH, bytes.len().into());
+        let (parts, body) = buffer(resp, limit, true).await.unwrap().into_parts();
+        let bytes = body::to_bytes(body).await.unwrap().to_vec();
+        (Response::from_parts(parts, ()), bytes)
+    }
+
+    fn truncated(resp: &Response<()>) -> bool {
+        resp.extensions().get::<Truncated>().is_some()
+    }
+
+    #[tokio::test]
+    async fn bodies_are_read_up_to_the_limit() {
+        let chunks: &[&[u8]] = &[b"abc", b"def", b"gh"];
+        let read = |limit| async move { read_body(&mut chunked(chunks), limit).await.unwrap() };
+        assert_eq!(read(None).await, (b"abcdefgh".to_vec(), false));
+        assert_eq!(read(Some(8)).await, (b"abcdefgh".to_vec(), false));
+        assert_eq!(read(Some(7)).await, (b"abcdefg".to_vec(), true));
+        assert_eq!(read(Some(4)).await, (b"abcd".to_vec(), true));
+        assert_eq!(read(Some(3)).await, (b"abc".to_vec(), true));
+        assert_eq!(read(Some(0)).await, (vec![], true));
+        assert_eq!(read_body(&mut Body::empty(), Some(0)).await.unwrap(), (vec![], false));
+    }
+
+    #[tokio::test]
+    async fn gzip_bodies_are_decoded() {
+        let (resp, body) = decoded(&["gzip"], gzip(TEXT), None).await;
+        assert_eq!(body, TEXT);
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+        assert!(!truncated(&resp));
+    }
+
+    #[tokio::test]
+    async fn deflate_bodies_may_be_zlib_wrapped_or_raw() {
+        assert_eq!(decoded(&["deflate"], zlib(TEXT), None).await.1, TEXT);
+        assert_eq!(decoded(&["Deflate"], deflate(TEXT), None).await.1, TEXT);
+    }
+
+    #[tokio::test]
+    async fn codings_are_reversed_in_the_opposite_order() {
+        let bytes = zstd::encode_all(gzip(&zlib(TEXT)).as_slice(), 0).unwrap();
+        assert_eq!(decoded(&["deflate, gzip", "zstd"], bytes.clone(), None).await.1, TEXT);
+        assert_eq!(decoded(&["deflate,gzip,zstd"], bytes.clone(), None).await.1, TEXT);
+        // In the wrong order the body doesn't decode, and is left as it was sent
+        let (resp, body) = decoded(&["zstd, gzip, deflate"], bytes.clone(), None).await;
+        assert_eq!(body, bytes);
+        assert!(resp.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn unsupported_codings_leave_the_body_as_it_was() {
+        let bytes = gzip(TEXT);
+        let (resp, body) = decoded(&["gzip, compress"], bytes.clone(), None).await;
+        assert_eq!(body, bytes);
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip, compress");
+        assert_eq!(resp.headers()[CONTENT_LENGTH], bytes.len().to_string().as_str());
+        assert!(!truncated(&resp));
+
+        let (resp, body) = decoded(&["gzip"], TEXT.to_vec(), None).await;
+        assert_eq!(body, TEXT);
+        assert!(resp.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn decoded_bodies_are_cut_off_at_the_limit() {
+        let bytes = gzip(&TEXT.repeat(100));
+        assert!(bytes.len() < TEXT.len() * 3);
+
+        let (resp, body) = decoded(&["gzip"], bytes.clone(), Some(TEXT.len() * 3)).await;
+        assert_eq!(body, TEXT.repeat(3));
+        assert!(truncated(&resp));
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+
+        let limit = Some(TEXT.len() * 100);
+        let (resp, body) = decoded(&["gzip"], bytes.clone(), limit).await;
+        assert_eq!(body, TEXT.repeat(100));
+        assert!(!truncated(&resp));
+    }
+
+    #[tokio::test]
+    async fn bodies_cut_off_are_not_decoded() {
+        let bytes = gzip(TEXT);
+        let (resp, body) = decoded(&["gzip"], bytes.clone(), Some(bytes.len() - 1)).await;
+        assert_eq!(body, bytes[..bytes.len() - 1]);
+        assert!(truncated(&resp));
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+    }
+
+    #[tokio::test]
+    async fn bodies_are_not_decoded_unless_asked() {
+        let bytes = gzip(TEXT);
+        let mut resp = Response::new(Body::from(bytes.clone()));
+        resp.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let resp = buffer(resp, None, false).await.unwrap();
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(body::to_bytes(resp.into_body()).await.unwrap(), bytes);
+    }
+}
@@ -1,24 +1,388 @@
 //! Intruder
 use anyhow::{anyhow, Context, Result};
 
+use async_stream::stream;
+
+use futures::future::join_all;
 use futures::{stream, Stream, StreamExt};
 
+use itertools::Itertools;
+
 use hyper::client::HttpConnector;
-use hyper::{Body, Client, Request, Response};
+use hyper::header::{
+    HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION,
+    SET_COOKIE,
+};
+use hyper::http::response;
+use hyper::{body, Body, Client, Method, Request, Response, StatusCode, Uri, Version};
+
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+
+use regex::bytes::Regex;
 
+use tokio::io::AsyncWriteExt;
+
+use std::collections::{HashSet, VecDeque};
 use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::request_template::{AttackType, ReqTemplateFile, RequestTemplate};
+use crate::encoding;
+use crate::payloads::{self, PayloadSource};
+use crate::request_template::{AttackType, RawTemplate, ReqTemplateFile, RequestTemplate};
+use crate::rules::Rules;
+use crate::tls;
+use crate::wire::{self, Connection};
 
 pub struct IntruderConfig {
     pub req_f: PathBuf,
-    pub pass_f: PathBuf,
+    pub payloads: Vec<Box<dyn PayloadSource>>,
+    /// Memory in bytes for removing duplicate payloads, duplicates are kept if None
+    pub dedupe: Option<usize>,
+    pub rules_f: Option<PathBuf>,
     pub pattern: String,
     pub concurrent_requests: usize,
     pub attack_type: AttackType,
+    /// Take every payload from the previous response instead of the payload sources
+    pub recursive_grep: Option<RecursiveGrep>,
+    /// Release the requests in synchronized batches of `concurrent_requests` (last-byte sync)
+    pub race: bool,
+    /// Send the template bytes verbatim over a raw socket instead of through hyper
+    pub raw: bool,
+    /// Recompute the `Content-Length` header in raw mode
+    pub fix_length: bool,
+    /// Connect with TLS
+    pub tls: bool,
+    /// Connection target (e.g. `https://host:port`) overriding the host from the template
+    pub target: Option<Uri>,
+    /// Place the payloads into the Host header and only report the hosts that differ from a baseline
+    pub vhost: Option<VirtualHosts>,
+    /// Append the payloads to a base url, recursing into the directories that are found
+    pub discover: Option<Discovery>,
+    /// Follow the redirects of the responses sent through hyper
+    pub redirects: Option<Redirects>,
+    /// Decode the response bodies according to their `Content-Encoding`
+    pub decompress: bool,
+    /// Bytes of every response body that are read, the rest is cut off
+    pub max_body_size: Option<usize>,
+}
+
+/// Configuration for Burp's "Recursive grep" payload type
+///
+/// The first request is sent with `initial`, and every following payload is extracted out of the
+/// previous response body with `extract` (its first capture group if it has one). The attack ends
+/// once nothing is extracted, or a payload that was already sent is extracted again (so cursors
+/// cycling back to an earlier page end the attack too).
+pub struct RecursiveGrep {
+    pub extract: Regex,
+    pub initial: Vec<u8>,
+}
+
+impl RecursiveGrep {
+    fn next_payload(&self, body: &[u8]) -> Option<Vec<u8>> {
+        let captures = self.extract.captures(body)?;
+        let found = captures.get(1).or_else(|| captures.get(0))?;
+        Some(found.as_bytes().to_vec())
+    }
+}
+
+/// Configuration for virtual host discovery
+///
+/// Every payload is sent as the Host header (`payload.domain` if a domain is set) to the same
+/// connection target, and compared against the response for a host that doesn't exist.
+pub struct VirtualHosts {
+    pub domain: Option<String>,
+}
+
+impl VirtualHosts {
+    fn host(&self, payload: &[u8]) -> Vec<u8> {
+        let mut host = payload.to_vec();
+        if let Some(domain) = &self.domain {
+            host.push(b'.');
+            host.extend_from_slice(domain.as_bytes());
+        }
+        host
+    }
+}
+
+/// Configuration for content discovery
+///
+/// Every payload is appended to the path of `base`, as it is and with each of the `extensions`
+/// (without the dot). The directories that are found are searched in turn, up to `max_depth`
+/// levels below `base`.
+pub struct Discovery {
+    pub base: Uri,
+    pub extensions: Vec<String>,
+    pub max_depth: usize,
+}
+
+impl Discovery {
+    /// Path of `base`, ending with a slash
+    fn base_path(&self) -> Vec<u8> {
+        let mut path = self.base.path().as_bytes().to_vec();
+        if !path.ends_with(b"/") {
+            path.push(b'/');
+        }
+        path
+    }
+
+    /// Paths relative to `base` for a word in `dir`, as it is and with every extension.
+    fn paths(&self, dir: &[u8], word: &[u8]) -> Vec<Vec<u8>> {
+        let mut path = dir.to_vec();
+        path.extend_from_slice(word);
+        let mut paths = vec![path.clone()];
+        for ext in &self.extensions {
+            let mut extended = path.clone();
+            extended.push(b'.');
+            extended.extend_from_slice(ext.as_bytes());
+            paths.push(extended);
+        }
+        paths
+    }
+
+    /// Whether the response shows that `path` is a directory: a redirect to it with a trailing
+    /// slash, or a 403 for a name without an extension.
+    fn is_directory(resp: &Response<Body>, path: &[u8]) -> bool {
+        let name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+        if name.is_empty() {
+            return false;
+        }
+        if resp.status().is_redirection() {
+            let location = match resp.headers().get(LOCATION) {
+                Some(location) => location.as_bytes(),
+                None => return false,
+            };
+            let location = location.split(|&b| b == b'?' || b == b'#').next().unwrap_or(location);
+            let mut dir = name.to_vec();
+            dir.push(b'/');
+            return location.ends_with(&dir);
+        }
+        resp.status() == StatusCode::FORBIDDEN && !name.contains(&b'.')
+    }
+}
+
+/// Policy for following redirects
+///
+/// At most `max` redirects are followed for a request, only to the host it was sent to if
+/// `same_host` is set. The cookies set along the way are sent with the following requests to the
+/// hosts they belong to.
+pub struct Redirects {
+    pub max: usize,
+    pub same_host: bool,
+}
+
+/// A redirect that was followed
+#[derive(Clone, Debug)]
+pub struct Hop {
+    pub status: StatusCode,
+    pub location: HeaderValue,
+}
+
+/// The redirects that were followed to get a response, in order
+///
+/// Attached to every response as an extension when following redirects.
+#[derive(Clone, Debug, Default)]
+pub struct RedirectChain(pub Vec<Hop>);
+
+impl RedirectChain {
+    /// Status of the first response of the chain, which is `last` if no redirect was followed
+    pub fn first_status(&self, last: StatusCode) -> StatusCode {
+        self.0.first().map_or(last, |hop| hop.status)
+    }
+}
+
+/// Resolves the `Location` of a redirect against the uri of the request it answers, as described
+/// in RFC 3986.
+fn resolve(base: &Uri, location: &[u8]) -> Result<Uri> {
+    let location = std::str::from_utf8(location)?;
+    let location = location.split('#').next().unwrap_or(location);
+    if location.contains("://") {
+        return Ok(location.parse()?);
+    }
+    let scheme = base.scheme_str().unwrap_or("http");
+    if location.starts_with("//") {
+        return Ok(format!("{}:{}", scheme, location).parse()?);
+    }
+    let authority = base.authority().ok_or(anyhow!("Uri without authority"))?;
+    let path = if location.is_empty() {
+        base.path_and_query().map_or("/", |path| path.as_str()).to_string()
+    } else if location.starts_with('?') {
+        format!("{}{}", base.path(), location)
+    } else if location.starts_with('/') {
+        remove_dot_segments(location)
+    } else {
+        // Relative to the directory of the current path
+        let dir = base.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+        remove_dot_segments(&format!("{}/{}", dir, location))
+    };
+    Ok(format!("{}://{}{}", scheme, authority, path).parse()?)
+}
+
+/// Removes the `.` and `..` segments from the path of an absolute path and query.
+fn remove_dot_segments(path_and_query: &str) -> String {
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+    // Starts with the empty segment before the leading slash, which stays
+    let mut kept = vec![];
+    for (i, &segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        match segment {
+            "." | ".." => {
+                if segment == ".." && kept.len() > 1 {
+                    kept.pop();
+                }
+                // A path ending in a dot segment ends in a slash
+                if last {
+                    kept.push("");
+                }
+            }
+            segment => kept.push(segment),
+        }
+    }
+    let mut path = kept.join("/");
+    if let Some(query) = query {
+        path.push('?');
+        path.push_str(query);
+    }
+    path
+}
+
+/// A cookie kept while following redirects
+///
+/// Cookies are sent only to the host they belong to: the host that set them, or the domain (and
+/// its subdomains) of their `Domain` attribute. The other attributes are ignored.
+#[derive(Clone, Debug, PartialEq)]
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    subdomains: bool,
+}
+
+impl Cookie {
+    fn matches(&self, host: &str) -> bool {
+        host == self.domain
+            || (self.subdomains
+                && host
+                    .strip_suffix(&self.domain)
+                    .is_some_and(|sub| sub.ends_with('.')))
+    }
+}
+
+/// Merges the cookies set by a response from `host` into the jar. Cookies whose `Domain` the host
+/// isn't part of are rejected.
+fn set_cookies(jar: &mut Vec<Cookie>, host: &str, headers: &HeaderMap) {
+    for set_cookie in headers.get_all(SET_COOKIE) {
+        let set_cookie = String::from_utf8_lossy(set_cookie.as_bytes());
+        let mut attrs = set_cookie.split(';');
+        let (name, value) = match attrs.next().unwrap_or_default().split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        let domain = attrs
+            .filter_map(|attr| attr.split_once('='))
+            .filter(|(key, _)| key.trim().eq_ignore_ascii_case("domain"))
+            .map(|(_, domain)| domain.trim().trim_start_matches('.').to_ascii_lowercase())
+            .next_back()
+            .filter(|domain| !domain.is_empty());
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: host.to_string(),
+            subdomains: false,
+        };
+        if let Some(domain) = domain {
+            cookie.domain = domain;
+            cookie.subdomains = true;
+            if !cookie.matches(host) {
+                continue;
+            }
+        }
+        jar.retain(|old| old.name != cookie.name || old.domain != cookie.domain);
+        jar.push(cookie);
+    }
+}
+
+/// The `Cookie` header for a request to `host`, None if no cookie belongs to it.
+fn cookie_header(jar: &[Cookie], host: &str) -> Result<Option<HeaderValue>> {
+    let mut cookies = jar
+        .iter()
+        .filter(|cookie| cookie.matches(host))
+        .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+        .peekable();
+    if cookies.peek().is_none() {
+        return Ok(None);
+    }
+    Ok(Some(HeaderValue::from_str(&cookies.join("; "))?))
+}
+
+/// The parts of a response that tell virtual hosts apart
+///
+/// Default virtual hosts often echo the host they were asked for, in a redirect to it or in an
+/// error page, so the host the request was sent for is removed from the body and the `Location`
+/// header first, making the responses for different hosts comparable.
+#[derive(Clone, Debug, PartialEq)]
+struct Baseline {
+    status: StatusCode,
+    length: usize,
+    location: Option<Vec<u8>>,
+}
+
+impl Baseline {
+    fn new(parts: &response::Parts, body: &[u8], host: &[u8]) -> Self {
+        Self {
+            status: parts.status,
+            length: strip_host(body, host).len(),
+            location: parts
+                .headers
+                .get(LOCATION)
+                .map(|location| strip_host(location.as_bytes(), host)),
+        }
+    }
+}
+
+/// Removes every occurrence of the host, ignoring ASCII case
+fn strip_host(bytes: &[u8], host: &[u8]) -> Vec<u8> {
+    if host.is_empty() {
+        return bytes.to_vec();
+    }
+    let mut stripped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].len() >= host.len() && bytes[i..i + host.len()].eq_ignore_ascii_case(host) {
+            i += host.len();
+        } else {
+            stripped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    stripped
+}
+
+/// The requests built for a payload, along with the payload
+type PayloadRequests = (Result<Vec<Request<Body>>>, Vec<u8>);
+
+/// Error for a payload that was not sent successfully, its message is the payload
+fn payload_error(payload: &[u8]) -> anyhow::Error {
+    anyhow!(String::from_utf8_lossy(payload).into_owned())
+}
+
+/// A connection on which a request was sent, all but its last byte
+struct Prepared {
+    stream: Box<dyn Connection>,
+    last: u8,
+    /// Whether the request is a `HEAD` request, whose response has no body
+    head: bool,
+}
+
+/// The template requests are created from
+enum Template {
+    Parsed(Box<RequestTemplate>),
+    /// Sent as it is written, in raw socket mode
+    Raw(RawTemplate),
 }
 
 /// Struct for managing the bruteforcing process
@@ -26,72 +390,1032 @@ pub struct IntruderConfig {
 /// The Intruder struct stores the [RequestTemplate] for creating new requests, the client for sending said
 /// requests and any configuration parameters relevant to the bruteforcing process.
 pub struct Intruder {
-    client: Client<HttpConnector>,
-    req_templ: RequestTemplate,
+    client: Client<HttpsConnector<HttpConnector>>,
+    templ: Template,
+    rules: Option<Rules>,
+    /// False when sending the template unchanged, as with null payloads
+    substitute: bool,
     pub config: IntruderConfig,
 }
 
 impl Intruder {
     /// Create new Intruder
     pub fn new(config: IntruderConfig) -> Result<Self> {
-        Ok(Intruder {
-            client: Client::new(),
-            req_templ: RequestTemplate::try_from(ReqTemplateFile::new(
+        let substitute = config.payloads.iter().any(|source| source.substitutes());
+        if substitute && config.payloads.iter().any(|source| !source.substitutes()) {
+            return Err(anyhow!(
+                "Null payloads can't be combined with other payload sources"
+            ));
+        }
+
+        let templ = if config.raw {
+            Template::Raw(RawTemplate::new(
+                File::open(&config.req_f)?,
+                &config.pattern,
+                config.target.clone(),
+                config.tls,
+                config.fix_length,
+            )?)
+        } else {
+            let mut req_templ = RequestTemplate::try_from(ReqTemplateFile::new(
                 File::open(&config.req_f)?,
                 &config.pattern,
                 config.attack_type,
-            )?)?,
+            )?)?;
+            if config.tls {
+                req_templ.use_tls();
+            }
+            if let Some(target) = &config.target {
+                req_templ.set_target(target.clone())?;
+            }
+            if let Some(discovery) = &config.discover {
+                req_templ.set_target(discovery.base.clone())?;
+            }
+            Template::Parsed(Box::new(req_templ))
+        };
+
+        let version = match &templ {
+            Template::Parsed(req_templ) => req_templ.version(),
+            Template::Raw(_) => Version::HTTP_11,
+        };
+        Ok(Intruder {
+            client: Self::client(version),
+            templ,
+            rules: config
+                .rules_f
+                .as_deref()
+                .map(Rules::from_file)
+                .transpose()?,
+            substitute,
             config,
         })
     }
 
+    /// The parsed template, which every mode but the raw socket mode creates its requests from
+    fn req_templ(&self) -> Result<&RequestTemplate> {
+        match &self.templ {
+            Template::Parsed(req_templ) => Ok(req_templ),
+            Template::Raw(_) => Err(anyhow!("Not supported in raw mode")),
+        }
+    }
+
+    /// Creates the client for the HTTP version of the template
+    ///
+    /// HTTP/2 is spoken with prior knowledge over plain connections (h2c) and negotiated with ALPN
+    /// over TLS, with the requests multiplexed on a single connection. Any other version uses
+    /// HTTP/1.1 connections.
+    fn client(version: Version) -> Client<HttpsConnector<HttpConnector>> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls::client_config())
+            .https_or_http();
+        if version == Version::HTTP_2 {
+            Client::builder()
+                .http2_only(true)
+                .build(connector.enable_http2().build())
+        } else {
+            Client::builder().build(connector.enable_http1().build())
+        }
+    }
+
     /// Send a single request, returns a tuple containg the response and the payload
     async fn send_reqs(
         &self,
         reqs: Vec<Request<Body>>,
-        payload: String,
-    ) -> Result<(Vec<Response<Body>>, String)> {
+        payload: Vec<u8>,
+    ) -> Result<(Vec<Response<Body>>, Vec<u8>)> {
         let mut resps = vec![];
         for req in reqs {
-            match self.client.request(req).await.context(payload.clone()) {
+            let resp = match &self.config.redirects {
+                Some(redirects) => self.follow(req, redirects).await,
+                None => self.client.request(req).await.map_err(Into::into),
+            };
+            match resp {
                 Ok(out) => resps.push(out),
-                Err(_) => return Err(anyhow!(payload)),
+                Err(_) => return Err(payload_error(&payload)),
+            };
+        }
+        match self.buffer(resps).await {
+            Ok(resps) => Ok((resps, payload)),
+            Err(_) => Err(payload_error(&payload)),
+        }
+    }
+
+    /// Reads the response bodies into memory, up to the size limit, and decodes them according
+    /// to their `Content-Encoding` unless the raw bytes are kept
+    ///
+    /// Every body is read as soon as its response arrives, whatever is done with it later, so
+    /// that the connection can be reused.
+    async fn buffer(&self, resps: Vec<Response<Body>>) -> Result<Vec<Response<Body>>> {
+        let mut buffered = vec![];
+        for resp in resps {
+            let resp = encoding::buffer(resp, self.config.max_body_size, self.config.decompress);
+            buffered.push(resp.await?);
+        }
+        Ok(buffered)
+    }
+
+    /// Sends a request and follows the redirects of its responses
+    ///
+    /// 303s, and 301s or 302s to a POST, are followed with a GET without a body, while the other
+    /// redirects repeat the request. The `Host` and `Authorization` headers are dropped when the
+    /// host changes, and the cookies (of the template too) only go to the hosts they belong to.
+    /// The final response carries the chain in a [RedirectChain] extension, the bodies of the
+    /// redirects are discarded.
+    async fn follow(&self, req: Request<Body>, redirects: &Redirects) -> Result<Response<Body>> {
+        let (parts, body) = req.into_parts();
+        let mut body = body::to_bytes(body).await?;
+        let (mut method, mut uri, version, mut headers) =
+            (parts.method, parts.uri, parts.version, parts.headers);
+        let host = |uri: &Uri| uri.host().unwrap_or_default().to_ascii_lowercase();
+        // Cookies of the template belong to the host it is sent to, and are overridden by the
+        // ones set along the way
+        let mut jar = vec![];
+        for cookie in headers.get_all(COOKIE) {
+            for pair in String::from_utf8_lossy(cookie.as_bytes()).split(';') {
+                if let Some((name, value)) = pair.split_once('=') {
+                    jar.push(Cookie {
+                        name: name.trim().to_string(),
+                        value: value.trim().to_string(),
+                        domain: host(&uri),
+                        subdomains: false,
+                    });
+                }
+            }
+        }
+        let origin = host(&uri);
+        let mut chain = vec![];
+        loop {
+            let mut req = Request::new(Body::from(body.clone()));
+            *req.method_mut() = method.clone();
+            *req.uri_mut() = uri.clone();
+            *req.version_mut() = version;
+            *req.headers_mut() = headers.clone();
+            let mut resp = self.client.request(req).await?;
+
+            let status = resp.status();
+            let next = match resp.headers().get(LOCATION) {
+                Some(location) if status.is_redirection() && chain.len() < redirects.max => {
+                    resolve(&uri, location.as_bytes())
+                        .ok()
+                        .map(|next| (location.clone(), next))
+                }
+                _ => None,
+            };
+            let (location, next) = match next {
+                Some((location, next))
+                    if !redirects.same_host || host(&next) == origin =>
+                {
+                    (location, next)
+                }
+                _ => {
+                    resp.extensions_mut().insert(RedirectChain(chain));
+                    return Ok(resp);
+                }
+            };
+
+            set_cookies(&mut jar, &host(&uri), resp.headers());
+            encoding::read_body(resp.body_mut(), self.config.max_body_size).await?;
+            chain.push(Hop { status, location });
+
+            if status == StatusCode::SEE_OTHER
+                || (method == Method::POST
+                    && matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND))
+            {
+                method = Method::GET;
+                body = body::Bytes::new();
+                headers.remove(CONTENT_TYPE);
+                headers.remove(CONTENT_LENGTH);
+            }
+            if next.authority() != uri.authority() {
+                headers.remove(HOST);
+                headers.remove(AUTHORIZATION);
+            }
+            match cookie_header(&jar, &host(&next))? {
+                Some(cookies) => headers.insert(COOKIE, cookies),
+                None => headers.remove(COOKIE),
             };
+            uri = next;
         }
-        Ok((resps, payload))
     }
 
     fn get_reqs<T>(
         &self,
         payloads: T,
-    ) -> impl Iterator<Item = (Result<Vec<Request<Body>>>, String)> + '_
+    ) -> impl Iterator<Item = PayloadRequests> + '_
     where
-        T: IntoIterator<Item = String> + 'static,
+        T: IntoIterator<Item = Vec<u8>> + 'static,
     {
         payloads
             .into_iter()
-            .map(|payload| (self.req_templ.replace_then_request(&payload), payload))
-            .filter(|req| req.0.is_ok())
+            .map(|payload| {
+                let reqs = self.req_templ().and_then(|req_templ| {
+                    if self.substitute {
+                        req_templ.replace_then_request(&payload)
+                    } else {
+                        req_templ.request()
+                    }
+                });
+                (reqs, payload)
+            })
     }
 
-    pub fn get_payload_buffer(&self) -> impl Iterator<Item = String> {
-        // Iterator for the passwords with any errors filtered out
-        BufReader::new(File::open(&self.config.pass_f).unwrap())
-            .lines()
-            .filter_map(|payload| payload.ok())
+    /// Iterator over every payload source in turn, with the rules applied and duplicates removed
+    ///
+    /// Payloads that can't be read come back as errors, which should end the attack.
+    pub fn get_payload_buffer(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>>>> {
+        let words: Vec<_> = self
+            .config
+            .payloads
+            .iter()
+            .map(|source| source.payloads())
+            .try_collect()?;
+        let words = words.into_iter().flatten();
+
+        let payloads: Box<dyn Iterator<Item = Result<Vec<u8>>>> = match &self.rules {
+            Some(rules) => Box::new(rules.apply(words)),
+            None => Box::new(words),
+        };
+        Ok(match self.config.dedupe {
+            Some(bytes) => Box::new(payloads::dedupe(payloads, bytes)),
+            None => payloads,
+        })
+    }
+
+    /// Number of payloads that will be sent, None if any of the sources can't tell
+    pub fn payload_count(&self) -> Result<Option<usize>> {
+        // Known only as the payloads are sent
+        if self.config.recursive_grep.is_some() || self.config.discover.is_some() {
+            return Ok(None);
+        }
+        let mut words = 0;
+        for source in &self.config.payloads {
+            match source.count()? {
+                Some(count) => words += count as usize,
+                None => return Ok(None),
+            }
+        }
+        if self.config.dedupe.is_some() {
+            // Only known after filtering every payload
+            return Ok(None);
+        }
+        Ok(Some(words * self.rules.as_ref().map_or(1, Rules::count)))
+    }
+
+    /// Creates a stream of responses where each payload is extracted from the previous response
+    ///
+    /// Requests are always sent one at a time, and the payloads that were sent are kept to stop
+    /// at the first one that comes back. The response bodies are read for the extraction, and are
+    /// replaced by the buffered bytes.
+    pub fn recursive_grep<'a>(
+        &'a self,
+        grep: &'a RecursiveGrep,
+    ) -> impl Stream<Item = Result<(Vec<Response<Body>>, Vec<u8>)>> + 'a {
+        stream! {
+            let mut sent = HashSet::new();
+            let mut next = Some(grep.initial.clone());
+            while let Some(payload) = next.take() {
+                sent.insert(payload.clone());
+                match self.send_recursive(payload, grep).await {
+                    Ok((resps, extracted)) => {
+                        next = extracted.filter(|payload| !sent.contains(payload));
+                        yield Ok(resps);
+                    }
+                    Err(err) => yield Err(err),
+                }
+            }
+        }
+    }
+
+    /// Sends the requests for a recursive grep payload, returning the next payload if any
+    async fn send_recursive(
+        &self,
+        payload: Vec<u8>,
+        grep: &RecursiveGrep,
+    ) -> Result<((Vec<Response<Body>>, Vec<u8>), Option<Vec<u8>>)> {
+        let reqs = self
+            .req_templ()
+            .and_then(|req_templ| req_templ.replace_then_request(&payload))
+            .map_err(|_| payload_error(&payload))?;
+        let (resps, payload) = self.send_reqs(reqs, payload).await?;
+
+        let mut next = None;
+        let mut buffered = vec![];
+        for resp in resps {
+            let (parts, body) = resp.into_parts();
+            let bytes = body::to_bytes(body)
+                .await
+                .map_err(|_| payload_error(&payload))?;
+            if next.is_none() {
+                next = grep.next_payload(&bytes);
+            }
+            buffered.push(Response::from_parts(parts, Body::from(bytes)));
+        }
+        Ok(((buffered, payload), next))
+    }
+
+    /// Creates a stream of responses where the requests are sent in synchronized batches
+    ///
+    /// Each request of a batch of `concurrent_requests` payloads gets its own connection, on which
+    /// everything but its last byte is sent. Once every request of the batch got that far, all the
+    /// last bytes are sent at once, so that the requests arrive at the server together.
+    pub async fn race<T>(
+        &self,
+        payloads: T,
+    ) -> Result<impl Stream<Item = Result<(Vec<Response<Body>>, Vec<u8>)>> + '_>
+    where
+        T: IntoIterator<Item = Vec<u8>> + 'static,
+    {
+        Ok(stream::iter(self.get_reqs(payloads))
+            .chunks(self.config.concurrent_requests)
+            .then(move |batch| self.race_batch(batch))
+            .flat_map(stream::iter))
+    }
+
+    async fn race_batch(
+        &self,
+        batch: Vec<PayloadRequests>,
+    ) -> Vec<Result<(Vec<Response<Body>>, Vec<u8>)>> {
+        let prepared = join_all(
+            batch
+                .into_iter()
+                .map(|(reqs, payload)| async move { (Self::race_prepare(reqs).await, payload) }),
+        )
+        .await;
+
+        // The last bytes are all written while the futures are first polled, before any of them
+        // waits for its response
+        join_all(prepared.into_iter().map(|(conns, payload)| async move {
+            let resps = match Self::race_release(conns, self.config.max_body_size).await {
+                Ok(resps) => self.buffer(resps).await,
+                Err(err) => Err(err),
+            };
+            match resps {
+                Ok(resps) => Ok((resps, payload)),
+                Err(_) => Err(payload_error(&payload)),
+            }
+        }))
+        .await
+    }
+
+    /// Connects and sends all but the last byte of every request
+    async fn race_prepare(reqs: Result<Vec<Request<Body>>>) -> Result<Vec<Prepared>> {
+        let mut conns = vec![];
+        for req in reqs? {
+            let uri = req.uri().clone();
+            let head = req.method() == Method::HEAD;
+            let mut bytes = wire::serialize(req).await?;
+            let last = bytes.pop().ok_or(anyhow!("Empty request"))?;
+            let mut stream = wire::connect(&uri).await?;
+            stream.write_all(&bytes).await?;
+            conns.push(Prepared { stream, last, head });
+        }
+        Ok(conns)
+    }
+
+    /// Sends the last byte of every request, then reads the responses
+    async fn race_release(
+        conns: Result<Vec<Prepared>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Response<Body>>> {
+        let mut conns = conns?;
+        for conn in conns.iter_mut() {
+            conn.stream.write_all(&[conn.last]).await?;
+        }
+        let mut resps = vec![];
+        for conn in conns.iter_mut() {
+            resps.push(wire::read_response(&mut conn.stream, conn.head, limit).await?);
+        }
+        Ok(resps)
+    }
+
+    /// Creates a stream of responses where the template is sent verbatim over raw sockets
+    ///
+    /// Every request gets its own connection, and the response is read with a lenient parser.
+    pub async fn raw<T>(
+        &self,
+        payloads: T,
+    ) -> Result<impl Stream<Item = Result<(Vec<Response<Body>>, Vec<u8>)>> + '_>
+    where
+        T: IntoIterator<Item = Vec<u8>> + 'static,
+    {
+        let raw = match &self.templ {
+            Template::Raw(raw) => raw,
+            Template::Parsed(_) => return Err(anyhow!("Template was not loaded in raw mode")),
+        };
+        let futures = payloads
+            .into_iter()
+            .map(move |payload| self.send_raw(raw, payload));
+        Ok(stream::iter(futures).buffer_unordered(self.config.concurrent_requests))
+    }
+
+    async fn send_raw(
+        &self,
+        raw: &RawTemplate,
+        payload: Vec<u8>,
+    ) -> Result<(Vec<Response<Body>>, Vec<u8>)> {
+        let req = if self.substitute {
+            raw.replace(&payload)
+        } else {
+            raw.request()
+        };
+        let resp = async {
+            let mut conn = wire::connect(raw.uri()).await?;
+            conn.write_all(&req).await?;
+            let head = req.starts_with(b"HEAD ");
+            let resp = wire::read_response(&mut conn, head, self.config.max_body_size).await?;
+            self.buffer(vec![resp]).await
+        };
+        match resp.await {
+            Ok(resps) => Ok((resps, payload)),
+            Err(_) => Err(payload_error(&payload)),
+        }
+    }
+
+    /// Creates a stream of responses for virtual host discovery
+    ///
+    /// A baseline is fetched first with a random host, then the payloads are placed into the
+    /// Host header while the connection target stays the same. Only the responses that differ
+    /// from the baseline (in status, body length or `Location`) are kept, the payloads whose
+    /// responses match it come back without any. Each response is compared with the host it was
+    /// sent for removed from its body and `Location`, so that a default virtual host echoing the
+    /// host doesn't make every payload differ. The response bodies are buffered.
+    pub async fn vhost<'a, T>(
+        &'a self,
+        payloads: T,
+        vhosts: &'a VirtualHosts,
+    ) -> Result<impl Stream<Item = Result<(Vec<Response<Body>>, Vec<u8>)>> + 'a>
+    where
+        T: IntoIterator<Item = Vec<u8>> + 'static,
+    {
+        let nonce = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let probe = format!("rip-intruder-{:x}", nonce).into_bytes();
+        let (resps, probe) = self
+            .send_vhost(probe, vhosts, None)
+            .await
+            .context("Could not fetch the baseline response")?;
+        let (parts, body) = resps
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("No baseline response"))?
+            .into_parts();
+        let baseline = Baseline::new(&parts, &body::to_bytes(body).await?, &vhosts.host(&probe));
+
+        let futures = payloads
+            .into_iter()
+            .map(move |payload| self.send_vhost(payload, vhosts, Some(baseline.clone())));
+        Ok(stream::iter(futures).buffer_unordered(self.config.concurrent_requests))
+    }
+
+    /// Sends the template with the payload as the Host header, dropping the responses that
+    /// match the baseline
+    async fn send_vhost(
+        &self,
+        payload: Vec<u8>,
+        vhosts: &VirtualHosts,
+        baseline: Option<Baseline>,
+    ) -> Result<(Vec<Response<Body>>, Vec<u8>)> {
+        let host = vhosts.host(&payload);
+        let reqs = self.req_templ().and_then(|req_templ| {
+            let host = HeaderValue::from_bytes(&host)?;
+            let mut reqs = req_templ.request()?;
+            for req in &mut reqs {
+                req.headers_mut().insert(HOST, host.clone());
+            }
+            Ok(reqs)
+        });
+        let reqs = reqs.map_err(|_| payload_error(&payload))?;
+        let (resps, payload) = self.send_reqs(reqs, payload).await?;
+
+        let mut differing = vec![];
+        for resp in resps {
+            let (parts, body) = resp.into_parts();
+            let bytes = body::to_bytes(body)
+                .await
+                .map_err(|_| payload_error(&payload))?;
+            if baseline.as_ref() != Some(&Baseline::new(&parts, &bytes, &host)) {
+                differing.push(Response::from_parts(parts, Body::from(bytes)));
+            }
+        }
+        Ok((differing, payload))
+    }
+
+    /// Creates a stream of responses for content discovery
+    ///
+    /// The payloads are appended to the base path, with every extension. Once a directory has
+    /// been searched, the directories found in it are searched with the same payloads, so the
+    /// new paths are fed into the same stream. The payloads are buffered up front, as every
+    /// directory is searched with them, and the streamed payloads are the paths relative to the
+    /// base.
+    pub fn discover<'a, T>(
+        &'a self,
+        payloads: T,
+        discovery: &'a Discovery,
+    ) -> impl Stream<Item = Result<(Vec<Response<Body>>, Vec<u8>)>> + 'a
+    where
+        T: IntoIterator<Item = Vec<u8>>,
+    {
+        let words: Vec<_> = payloads.into_iter().collect();
+        stream! {
+            let mut dirs = VecDeque::from([(Vec::new(), 0)]);
+            let mut found = HashSet::new();
+            while let Some((dir, depth)) = dirs.pop_front() {
+                let paths = words.iter().flat_map(|word| discovery.paths(&dir, word));
+                let mut resps = stream::iter(paths.map(|path| self.send_discover(discovery, path)))
+                    .buffer_unordered(self.config.concurrent_requests);
+                while let Some(result) = resps.next().await {
+                    if let Ok((resps, path)) = &result {
+                        if depth < discovery.max_depth
+                            && resps.iter().any(|resp| Discovery::is_directory(resp, path))
+                            && found.insert(path.clone())
+                        {
+                            let mut sub = path.clone();
+                            sub.push(b'/');
+                            dirs.push_back((sub, depth + 1));
+                        }
+                    }
+                    yield result;
+                }
+            }
+        }
+    }
+
+    async fn send_discover(
+        &self,
+        discovery: &Discovery,
+        path: Vec<u8>,
+    ) -> Result<(Vec<Response<Body>>, Vec<u8>)> {
+        let mut target = discovery.base_path();
+        target.extend_from_slice(&path);
+        let reqs = self
+            .req_templ()
+            .and_then(|req_templ| req_templ.request_to(&target))
+            .map_err(|_| payload_error(&path))?;
+        self.send_reqs(reqs, path).await
     }
 
     /// Creates a stream for asynchronously iterating over the responses for the provided payloads
     pub async fn bruteforce<T>(
         &self,
         payloads: T,
-    ) -> Result<impl Stream<Item = Result<(Vec<Response<Body>>, String)>> + '_>
+    ) -> Result<impl Stream<Item = Result<(Vec<Response<Body>>, Vec<u8>)>> + '_>
     where
-        T: IntoIterator<Item = String> + 'static,
+        T: IntoIterator<Item = Vec<u8>> + 'static,
     {
-        let futures = self
-            .get_reqs(payloads)
-            .map(|(req, payload)| self.send_reqs(req.unwrap(), payload));
+        let futures = self.get_reqs(payloads).map(move |(reqs, payload)| async move {
+            match reqs {
+                Ok(reqs) => self.send_reqs(reqs, payload).await,
+                Err(_) => Err(payload_error(&payload)),
+            }
+        });
         Ok(stream::iter(futures).buffer_unordered(self.config.concurrent_requests))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    use crate::payloads::{PayloadGenerator, Wordlist};
+
+    /// Writes a file for a test under a name unique to this run
+    fn temp_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let name = format!("rip-intruder-{}-{}", std::process::id(), name);
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    /// A config for attacking the template, with every option off
+    fn config(name: &str, template: &str) -> IntruderConfig {
+        IntruderConfig {
+            req_f: temp_file(name, template.as_bytes()),
+            payloads: vec![],
+            dedupe: None,
+            rules_f: None,
+            pattern: "§§".to_string(),
+            concurrent_requests: 1,
+            attack_type: AttackType::BatteringRam,
+            recursive_grep: None,
+            race: false,
+            raw: false,
+            fix_length: false,
+            tls: false,
+            target: None,
+            vhost: None,
+            discover: None,
+            redirects: None,
+            decompress: true,
+            max_body_size: None,
+        }
+    }
+
+    /// Serves the handler on a local port, over HTTP/2 only if `h2`, returning the authority to
+    /// connect to
+    fn serve<F>(h2: bool, handler: F) -> String
+    where
+        F: Fn(Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let authority = listener.local_addr().unwrap().to_string();
+        let make_service = make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let resp = handler(req);
+                    async move { Ok::<_, Infallible>(resp) }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener).unwrap().http2_only(h2);
+        tokio::spawn(server.serve(make_service));
+        authority
+    }
+
+    #[tokio::test]
+    async fn recursive_grep_stops_when_the_cursor_cycles() {
+        let authority = serve(false, |req| {
+            let cursor = req.uri().query().unwrap_or_default().trim_start_matches("cursor=");
+            let next = match cursor {
+                "start" => "a",
+                "a" => "b",
+                "b" => "c",
+                _ => "a",
+            };
+            Response::new(Body::from(format!("<a href=\"?cursor={}\">next</a>", next)))
+        });
+        let template = format!("GET /?cursor=§§ HTTP/1.1\r\nHost: {}\r\n\r\n", authority);
+        let intr = Intruder::new(config("grep.req", &template)).unwrap();
+        let grep = RecursiveGrep {
+            extract: Regex::new(r"cursor=(\w+)").unwrap(),
+            initial: b"start".to_vec(),
+        };
+
+        let sent = intr.recursive_grep(&grep).map(|result| {
+            let (resps, payload) = result.unwrap();
+            assert_eq!(resps.len(), 1);
+            String::from_utf8(payload).unwrap()
+        });
+        let sent: Vec<_> = timeout(Duration::from_secs(5), sent.collect()).await.unwrap();
+        assert_eq!(sent, ["start", "a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn recursive_grep_stops_when_nothing_is_extracted() {
+        let authority = serve(false, |req| match req.uri().query() {
+            Some("cursor=start") => Response::new(Body::from("cursor=end")),
+            _ => Response::new(Body::from("last page")),
+        });
+        let template = format!("GET /?cursor=§§ HTTP/1.1\r\nHost: {}\r\n\r\n", authority);
+        let intr = Intruder::new(config("grep-end.req", &template)).unwrap();
+        let grep = RecursiveGrep {
+            extract: Regex::new(r"cursor=\w+").unwrap(),
+            initial: b"start".to_vec(),
+        };
+
+        let sent = intr.recursive_grep(&grep).map(|result| result.unwrap().1);
+        let sent: Vec<_> = timeout(Duration::from_secs(5), sent.collect()).await.unwrap();
+        // Without a capture group, the whole match is the payload
+        assert_eq!(sent, [b"start".to_vec(), b"cursor=end".to_vec()]);
+    }
+
+    /// Sends the template once, unchanged, returning the response and its body
+    async fn send_one(config: IntruderConfig) -> (response::Parts, Vec<u8>) {
+        let intr = Intruder::new(config).unwrap();
+        let mut resps = intr.bruteforce([b"x".to_vec()]).await.unwrap();
+        let (resps, _) = timeout(Duration::from_secs(5), resps.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let (parts, body) = resps.into_iter().next().unwrap().into_parts();
+        (parts, body::to_bytes(body).await.unwrap().to_vec())
+    }
+
+    #[tokio::test]
+    async fn http2_templates_are_sent_over_h2c() {
+        let echo_version =
+            |req: Request<Body>| Response::new(Body::from(format!("{:?}", req.version())));
+        let authority = serve(true, echo_version);
+        let template = format!("GET / HTTP/2\r\nHost: {}\r\n\r\n", authority);
+        let (parts, body) = send_one(config("h2c.req", &template)).await;
+        assert_eq!(parts.version, Version::HTTP_2);
+        assert_eq!(body, b"HTTP/2.0");
+
+        let authority = serve(false, echo_version);
+        let template = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", authority);
+        let (parts, body) = send_one(config("http1.req", &template)).await;
+        assert_eq!(parts.version, Version::HTTP_11);
+        assert_eq!(body, b"HTTP/1.1");
+    }
+
+    #[test]
+    fn locations_resolve_against_the_request_uri() {
+        let base: Uri = "https://example.com:8443/list/items?page=1".parse().unwrap();
+        let resolve = |location: &str| resolve(&base, location.as_bytes()).unwrap().to_string();
+        assert_eq!(resolve("http://other.com/a?b"), "http://other.com/a?b");
+        assert_eq!(resolve("//cdn.example.com/x"), "https://cdn.example.com/x");
+        assert_eq!(resolve("/login?next=/list"), "https://example.com:8443/login?next=/list");
+        assert_eq!(resolve("details"), "https://example.com:8443/list/details");
+        assert_eq!(resolve("?page=2"), "https://example.com:8443/list/items?page=2");
+        assert_eq!(resolve(""), "https://example.com:8443/list/items?page=1");
+        assert_eq!(resolve("#top"), "https://example.com:8443/list/items?page=1");
+        assert_eq!(resolve("../up?x=../y"), "https://example.com:8443/up?x=../y");
+        assert_eq!(resolve("./"), "https://example.com:8443/list/");
+        assert_eq!(resolve("/a/./b/../../../c/.."), "https://example.com:8443/");
+        assert!(super::resolve(&base, b"http://bad host/").is_err());
+    }
+
+    fn set_cookie(jar: &mut Vec<Cookie>, host: &str, set_cookies: &[&str]) {
+        let mut headers = HeaderMap::new();
+        for set_cookie in set_cookies {
+            headers.append(SET_COOKIE, set_cookie.parse().unwrap());
+        }
+        super::set_cookies(jar, host, &headers);
+    }
+
+    fn cookies(jar: &[Cookie], host: &str) -> Option<String> {
+        cookie_header(jar, host)
+            .unwrap()
+            .map(|cookies| cookies.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn cookies_are_merged_into_the_jar() {
+        let mut jar = vec![];
+        let set = ["session=abc; Path=/; HttpOnly", "theme = dark ", "bad"];
+        set_cookie(&mut jar, "example.com", &set);
+        assert_eq!(cookies(&jar, "example.com").unwrap(), "session=abc; theme=dark");
+        set_cookie(&mut jar, "example.com", &["session=def; Secure"]);
+        assert_eq!(cookies(&jar, "example.com").unwrap(), "theme=dark; session=def");
+        // The same name from another host is another cookie
+        set_cookie(&mut jar, "other.com", &["session=xyz"]);
+        assert_eq!(cookies(&jar, "example.com").unwrap(), "theme=dark; session=def");
+        assert_eq!(cookies(&jar, "other.com").unwrap(), "session=xyz");
+    }
+
+    #[test]
+    fn cookies_only_go_to_the_hosts_they_belong_to() {
+        let mut jar = vec![];
+        set_cookie(&mut jar, "login.example.com", &["host=1", "wide=1; Domain=.Example.com"]);
+        set_cookie(&mut jar, "login.example.com", &["foreign=1; Domain=attacker.com"]);
+        set_cookie(&mut jar, "login.example.com", &["sibling=1; Domain=www.example.com"]);
+        assert_eq!(cookies(&jar, "login.example.com").unwrap(), "host=1; wide=1");
+        assert_eq!(cookies(&jar, "www.example.com").unwrap(), "wide=1");
+        assert_eq!(cookies(&jar, "example.com").unwrap(), "wide=1");
+        assert_eq!(cookies(&jar, "badexample.com"), None);
+        assert_eq!(cookies(&jar, "attacker.com"), None);
+    }
+
+    /// Serves a page echoing what a redirect led to, and returns its authority
+    fn landing() -> String {
+        serve(false, |req| {
+            let header = |name| {
+                req.headers()
+                    .get(name)
+                    .map_or("-", |value: &HeaderValue| value.to_str().unwrap())
+            };
+            let echo = format!(
+                "{} {} cookie={} auth={} host={} length={}",
+                req.method(),
+                req.uri(),
+                header(COOKIE),
+                header(AUTHORIZATION),
+                header(HOST),
+                header(CONTENT_LENGTH),
+            );
+            Response::new(Body::from(echo))
+        })
+    }
+
+    /// Follows the redirects of a server answering with `status` to every path but `/home`,
+    /// which it echoes.
+    async fn redirected(name: &str, status: u16, method: &str) -> (Vec<u16>, String) {
+        let authority = serve(false, move |req| {
+            if req.uri().path() == "/home" {
+                let has_body = req.headers().contains_key(CONTENT_LENGTH);
+                let echo = format!("{} {}", req.method(), has_body);
+                return Response::new(Body::from(echo));
+            }
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::from_u16(status).unwrap();
+            resp.headers_mut().insert(LOCATION, HeaderValue::from_static("/home"));
+            resp
+        });
+        let template = format!(
+            "{} /start HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\n\r\npin=0042",
+            method, authority
+        );
+        let mut config = config(name, &template);
+        config.redirects = Some(Redirects {
+            max: 10,
+            same_host: false,
+        });
+        let (parts, body) = send_one(config).await;
+        let chain = parts.extensions.get::<RedirectChain>().unwrap();
+        let statuses = chain.0.iter().map(|hop| hop.status.as_u16()).collect();
+        (statuses, String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn redirects_switch_to_get_like_browsers() {
+        assert_eq!(redirected("r303.req", 303, "PUT").await, (vec![303], "GET false".into()));
+        assert_eq!(redirected("r302.req", 302, "POST").await, (vec![302], "GET false".into()));
+        assert_eq!(redirected("r301.req", 301, "POST").await, (vec![301], "GET false".into()));
+        assert_eq!(redirected("r302put.req", 302, "PUT").await, (vec![302], "PUT true".into()));
+        assert_eq!(redirected("r307.req", 307, "POST").await, (vec![307], "POST true".into()));
+        assert_eq!(redirected("r308.req", 308, "POST").await, (vec![308], "POST true".into()));
+    }
+
+    #[tokio::test]
+    async fn redirects_to_other_hosts_drop_the_credentials() {
+        let landing = landing();
+        let port = landing.rsplit_once(':').unwrap().1.to_string();
+        let authority = serve(false, move |req| {
+            let location = match req.uri().path() {
+                "/login" => "/account".to_string(),
+                "/account" => format!("http://localhost:{}/landing", port),
+                _ => return Response::new(Body::from("lost")),
+            };
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, location)
+                .header(SET_COOKIE, "session=abc; HttpOnly")
+                .header(SET_COOKIE, "wide=1; Domain=localhost")
+                .body(Body::empty())
+                .unwrap()
+        });
+        let template = format!(
+            "GET /login HTTP/1.1\r\nHost: {}\r\nCookie: template=1\r\n\
+             Authorization: Basic eDp5\r\n\r\n",
+            authority
+        );
+        let mut config = config("cross-host.req", &template);
+        config.redirects = Some(Redirects {
+            max: 10,
+            same_host: false,
+        });
+        let (parts, body) = send_one(config).await;
+        let chain = parts.extensions.get::<RedirectChain>().unwrap();
+        assert_eq!(chain.0.len(), 2);
+        let body = String::from_utf8(body).unwrap();
+        let host = landing.replace("127.0.0.1", "localhost");
+        assert_eq!(body, format!("GET /landing cookie=- auth=- host={} length=-", host));
+    }
+
+    /// A wordlist followed by a generator, with the payloads 1 and a appearing twice
+    fn chained(name: &str, dedupe: Option<usize>) -> Intruder {
+        let mut config = config(name, "GET /§§ HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let words = temp_file(&format!("{}.txt", name), b"b\na\r\n1\na\n");
+        let numbers: PayloadGenerator = "numbers:0-2".parse().unwrap();
+        config.payloads = vec![Box::new(Wordlist::new(words)), Box::new(numbers)];
+        config.dedupe = dedupe;
+        Intruder::new(config).unwrap()
+    }
+
+    #[test]
+    fn payload_sources_are_chained_in_order() {
+        let intr = chained("chain", None);
+        let payloads: Vec<_> = intr.get_payload_buffer().unwrap().try_collect().unwrap();
+        let expected = ["b", "a", "1", "a", "0", "1", "2"];
+        assert_eq!(payloads, expected.map(|p| p.as_bytes().to_vec()));
+        assert_eq!(intr.payload_count().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn dedupe_applies_across_sources() {
+        let intr = chained("dedupe", Some(1024));
+        let payloads: Vec<_> = intr.get_payload_buffer().unwrap().try_collect().unwrap();
+        assert_eq!(payloads, ["b", "a", "1", "0", "2"].map(|p| p.as_bytes().to_vec()));
+        // Only known once the payloads have gone through the filter
+        assert_eq!(intr.payload_count().unwrap(), None);
+    }
+
+    /// When the first byte and the whole of a request arrived at the test server
+    struct Arrival {
+        first_byte: Instant,
+        complete: Instant,
+    }
+
+    /// Reads a request with a `Content-Length` body, timestamping its arrival
+    async fn receive(stream: &mut tokio::net::TcpStream) -> Arrival {
+        let mut buf = Vec::new();
+        let mut first_byte = None;
+        loop {
+            stream.read_buf(&mut buf).await.unwrap();
+            first_byte.get_or_insert_with(Instant::now);
+            let text = String::from_utf8_lossy(&buf).to_ascii_lowercase();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                if buf.len() >= end + 4 + length {
+                    break;
+                }
+            }
+        }
+        Arrival {
+            first_byte: first_byte.unwrap(),
+            complete: Instant::now(),
+        }
+    }
+
+    /// Answers `n` connections, returning when each request arrived
+    async fn timestamping_server(n: usize) -> (Uri, JoinHandle<Vec<Arrival>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+        let server = tokio::spawn(async move {
+            let mut conns = Vec::new();
+            for _ in 0..n {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                conns.push(tokio::spawn(async move {
+                    let arrival = receive(&mut stream).await;
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await
+                        .unwrap();
+                    arrival
+                }));
+            }
+            join_all(conns).await.into_iter().map(Result::unwrap).collect()
+        });
+        (uri, server)
+    }
+
+    #[tokio::test]
+    async fn race_releases_the_last_bytes_together() {
+        let (uri, server) = timestamping_server(5).await;
+        let reqs = (0..5)
+            .map(|i| {
+                Request::post(uri.clone())
+                    .body(Body::from(format!("pin={}", i)))
+                    .unwrap()
+            })
+            .collect();
+        let conns = Intruder::race_prepare(Ok(reqs)).await;
+        // Give everything but the last bytes time to arrive
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let released = Instant::now();
+        let resps = Intruder::race_release(conns, None).await.unwrap();
+        assert_eq!(resps.len(), 5);
+
+        let arrivals = server.await.unwrap();
+        for arrival in &arrivals {
+            assert!(arrival.first_byte < released);
+            assert!(arrival.complete >= released);
+        }
+        let completions = arrivals.iter().map(|arrival| arrival.complete);
+        let spread = completions.clone().max().unwrap() - completions.min().unwrap();
+        assert!(spread < Duration::from_millis(50), "{:?}", spread);
+    }
+
+    fn parts(status: u16, location: Option<&str>) -> response::Parts {
+        let mut builder = Response::builder().status(status);
+        if let Some(location) = location {
+            builder = builder.header(LOCATION, location);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn baselines_ignore_the_echoed_host() {
+        let probe = b"rip-intruder-1f.example.com";
+        let baseline = Baseline::new(
+            &parts(301, Some("https://rip-intruder-1f.example.com/")),
+            b"<address>Apache at rip-intruder-1f.example.com Port 80</address>",
+            probe,
+        );
+        let echoed = Baseline::new(
+            &parts(301, Some("https://WWW.example.com/")),
+            b"<address>Apache at www.example.com Port 80</address>",
+            b"www.example.com",
+        );
+        assert_eq!(baseline, echoed);
+
+        let moved = Baseline::new(
+            &parts(301, Some("https://www.example.com/login")),
+            b"<address>Apache at www.example.com Port 80</address>",
+            b"www.example.com",
+        );
+        assert_ne!(baseline, moved);
+        let longer = Baseline::new(
+            &parts(301, Some("https://www.example.com/")),
+            b"<address>Apache at www.example.com Port 8080</address>",
+            b"www.example.com",
+        );
+        assert_ne!(baseline, longer);
+        assert_ne!(baseline, Baseline::new(&parts(200, None), b"", probe));
+    }
+}
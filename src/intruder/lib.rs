@@ -1,2 +1,8 @@
+pub mod encoding;
 pub mod intruder;
+pub mod multipart;
+pub mod payloads;
 pub mod request_template;
+pub mod rules;
+pub mod tls;
+pub mod wire;
@@ -0,0 +1,228 @@
+//! Multipart
+//!
+//! This module houses the parser for `multipart/form-data` bodies, so that the markers in the
+//! parts of a template are replaced without breaking the framing of the body.
+use regex::bytes::{NoExpand, Regex as BytesRegex};
+
+/// A part of a `multipart/form-data` body
+///
+/// The head (field name, filename and Content-Type) and the content are kept as bytes, so that
+/// markers in either are replaced without the part being put back together differently.
+#[derive(Clone, Debug)]
+struct FormPart {
+    /// Everything from the end of the boundary to the empty line ending the head, inclusive
+    head: Vec<u8>,
+    /// Contents of the part
+    content: Vec<u8>,
+    /// The line ending in front of the next boundary
+    tail: Vec<u8>,
+}
+
+impl FormPart {
+    /// Parses the bytes between two boundaries.
+    fn parse(segment: &[u8]) -> Self {
+        // The first line is the rest of the boundary line, the head ends at the next empty one
+        let mut pos = segment
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(segment.len(), |pos| pos + 1);
+        while pos < segment.len() {
+            let end = segment[pos..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(segment.len(), |end| pos + end + 1);
+            let line = &segment[pos..end];
+            pos = end;
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            if line.strip_suffix(b"\r").unwrap_or(line).is_empty() {
+                break;
+            }
+        }
+
+        let rest = &segment[pos..];
+        let tail_len = if rest.ends_with(b"\r\n") {
+            2
+        } else {
+            rest.ends_with(b"\n") as usize
+        };
+        Self {
+            head: segment[..pos].to_vec(),
+            content: rest[..rest.len() - tail_len].to_vec(),
+            tail: rest[rest.len() - tail_len..].to_vec(),
+        }
+    }
+}
+
+/// Value of a `; key=value` parameter of a header value, unquoted.
+fn param(value: &str, key: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (k, v) = param.split_once('=')?;
+        k.trim()
+            .eq_ignore_ascii_case(key)
+            .then(|| v.trim().trim_matches('"').to_string())
+    })
+}
+
+/// A `multipart/form-data` body split into its parts
+#[derive(Clone, Debug)]
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<FormPart>,
+    preamble: Vec<u8>,
+    /// Everything after the closing boundary
+    epilogue: Vec<u8>,
+}
+
+impl Multipart {
+    /// Boundary from the value of a `Content-Type` header, if it is `multipart/form-data`.
+    pub fn boundary(content_type: &str) -> Option<String> {
+        let mime = content_type.split(';').next()?.trim();
+        if !mime.eq_ignore_ascii_case("multipart/form-data") {
+            return None;
+        }
+        param(content_type, "boundary").filter(|boundary| !boundary.is_empty())
+    }
+
+    /// Splits the body on the boundary, bodies that aren't terminated by a closing boundary are
+    /// not parsed.
+    pub fn parse(body: &[u8], boundary: &str) -> Option<Self> {
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let starts: Vec<usize> = body
+            .windows(delimiter.len())
+            .enumerate()
+            .filter(|&(i, window)| window == delimiter && (i == 0 || body[i - 1] == b'\n'))
+            .map(|(i, _)| i)
+            .collect();
+        let (&last, _) = starts.split_last()?;
+        let closing = &body[last + delimiter.len()..];
+        if !closing.starts_with(b"--") {
+            return None;
+        }
+
+        let parts = starts
+            .windows(2)
+            .map(|w| FormPart::parse(&body[w[0] + delimiter.len()..w[1]]))
+            .collect();
+        Some(Self {
+            boundary: boundary.to_string(),
+            parts,
+            preamble: body[..starts[0]].to_vec(),
+            epilogue: closing[2..].to_vec(),
+        })
+    }
+
+    /// Replace every match of the pattern with pw in the parts, then put the body back together.
+    ///
+    /// If a part now contains the boundary, a new one is picked so that the framing stays
+    /// intact, it is returned along with the body so the `Content-Type` header can be updated.
+    pub fn replace(&self, pattern: &BytesRegex, pw: &[u8]) -> (Vec<u8>, Option<String>) {
+        let replace = |bytes: &[u8]| pattern.replace_all(bytes, NoExpand(pw)).into_owned();
+        let preamble = replace(&self.preamble);
+        let parts: Vec<(Vec<u8>, Vec<u8>, &[u8])> = self
+            .parts
+            .iter()
+            .map(|part| (replace(&part.head), replace(&part.content), &part.tail[..]))
+            .collect();
+        let epilogue = replace(&self.epilogue);
+
+        let collides = |boundary: &str| {
+            let delimiter = format!("--{}", boundary).into_bytes();
+            let contains = |bytes: &[u8]| bytes.windows(delimiter.len()).any(|w| w == delimiter);
+            contains(&preamble)
+                || contains(&epilogue)
+                || parts
+                    .iter()
+                    .any(|(head, content, _)| contains(head) || contains(content))
+        };
+        let mut boundary = self.boundary.clone();
+        let mut i = 0;
+        while collides(&boundary) {
+            boundary = format!("{}{}", self.boundary, i);
+            i += 1;
+        }
+
+        let mut body = preamble;
+        for (head, content, tail) in parts {
+            body.extend_from_slice(b"--");
+            body.extend_from_slice(boundary.as_bytes());
+            body.extend_from_slice(&head);
+            body.extend_from_slice(&content);
+            body.extend_from_slice(tail);
+        }
+        body.extend_from_slice(format!("--{}--", boundary).as_bytes());
+        body.extend_from_slice(&epilogue);
+        let changed = (boundary != self.boundary).then_some(boundary);
+        (body, changed)
+    }
+
+    /// The `Content-Type` header value with the boundary parameter set to `boundary`.
+    pub fn content_type(&self, content_type: &[u8], boundary: &str) -> Vec<u8> {
+        let content_type = match std::str::from_utf8(content_type) {
+            Ok(content_type) => content_type,
+            Err(_) => return content_type.to_vec(),
+        };
+        let start = content_type
+            .to_ascii_lowercase()
+            .find("boundary=")
+            .map_or(0, |start| start + "boundary=".len());
+        let (params, value) = content_type.split_at(start);
+        format!("{}{}", params, value.replacen(&self.boundary, boundary, 1)).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"\xc2\xa7\xc2\xa7.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        contents \xc2\xa7\xc2\xa7\r\n\
+        --XyZ\r\nContent-Disposition: form-data; name=\"submit\"\r\n\r\nUpload\r\n--XyZ--\r\n";
+
+    fn pattern() -> BytesRegex {
+        BytesRegex::new("§§").unwrap()
+    }
+
+    #[test]
+    fn boundary_is_read_from_the_content_type() {
+        let boundary = Multipart::boundary("multipart/form-data; boundary=\"XyZ\"; charset=utf-8");
+        assert_eq!(boundary.as_deref(), Some("XyZ"));
+        assert_eq!(Multipart::boundary("text/plain; boundary=XyZ"), None);
+        assert_eq!(Multipart::boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn replacing_keeps_the_framing() {
+        let multipart = Multipart::parse(BODY, "XyZ").unwrap();
+        assert_eq!(multipart.parts.len(), 2);
+        let (body, boundary) = multipart.replace(&pattern(), b"shell.php");
+        assert_eq!(boundary, None);
+        let expected = String::from_utf8(BODY.to_vec()).unwrap().replace("§§", "shell.php");
+        assert_eq!(body, expected.as_bytes());
+    }
+
+    #[test]
+    fn payloads_containing_the_boundary_get_a_new_one() {
+        let multipart = Multipart::parse(BODY, "XyZ").unwrap();
+        let (body, boundary) = multipart.replace(&pattern(), b"a\r\n--XyZ--\r\n--XyZ0");
+        assert_eq!(boundary.as_deref(), Some("XyZ1"));
+
+        // The new body parses with the new boundary, and the payload stays inside its parts
+        let reparsed = Multipart::parse(&body, "XyZ1").unwrap();
+        assert_eq!(reparsed.parts.len(), 2);
+        assert_eq!(reparsed.parts[0].content, b"contents a\r\n--XyZ--\r\n--XyZ0");
+        assert!(body.ends_with(b"\r\n--XyZ1--\r\n"));
+
+        assert_eq!(
+            multipart.content_type(b"multipart/form-data; boundary=\"XyZ\"", "XyZ1"),
+            b"multipart/form-data; boundary=\"XyZ1\""
+        );
+    }
+
+    #[test]
+    fn unterminated_bodies_are_not_parsed() {
+        assert!(Multipart::parse(b"--XyZ\r\n\r\ncontent\r\n--XyZ\r\n", "XyZ").is_none());
+        assert!(Multipart::parse(b"no boundary at all", "XyZ").is_none());
+    }
+}
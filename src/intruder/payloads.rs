@@ -0,0 +1,1289 @@
+//! Payloads
+//!
+//! This module houses the [PayloadSource] trait, the sources implementing it (wordlists and the
+//! built-in payload generators, which lazily produce payloads so that no wordlist file has to
+//! be created beforehand) and the filter for removing duplicate payloads.
+use anyhow::{anyhow, Context, Error, Result};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use bzip2::bufread::MultiBzDecoder;
+
+use chrono::format::StrftimeItems;
+use chrono::{Datelike, Days, Months, NaiveDate};
+
+use flate2::bufread::MultiGzDecoder;
+
+use itertools::Itertools;
+
+use serde_json::Value;
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{prelude::*, BufReader};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use xz2::bufread::XzDecoder;
+
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Anything payloads can be read from
+pub trait PayloadSource {
+    /// Iterator over the payloads, which are raw bytes and need not be valid UTF-8
+    ///
+    /// A payload that can't be read (e.g. in a corrupt compressed file) comes back as an error,
+    /// the payloads after it are not read.
+    fn payloads(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>>>>;
+
+    /// Number of payloads, if it can be known before sending them
+    fn count(&self) -> Result<Option<u64>>;
+
+    /// Whether the payloads are placed into the template, or the template is sent unchanged
+    fn substitutes(&self) -> bool {
+        true
+    }
+}
+
+/// Reads the payloads from a wordlist file, one per line.
+///
+/// Files compressed with gzip, bzip2, xz or zstd are detected by their magic bytes and
+/// decompressed while reading.
+pub struct Wordlist {
+    path: PathBuf,
+}
+
+impl Wordlist {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl PayloadSource for Wordlist {
+    fn payloads(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>>>> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("Could not open {}", self.path.display()))?;
+        let path = self.path.clone();
+        Ok(Box::new(lines(decompress(BufReader::new(file))?).map(
+            move |line| line.with_context(|| format!("Could not read {}", path.display())),
+        )))
+    }
+
+    fn count(&self) -> Result<Option<u64>> {
+        Ok(Some(self.payloads()?.fold_ok(0, |count, _| count + 1)?))
+    }
+}
+
+/// Wraps the reader in a decoder if it starts with the magic bytes of gzip, bzip2, xz or zstd.
+fn decompress<R: BufRead + 'static>(mut reader: R) -> Result<Box<dyn BufRead>> {
+    let magic = reader.fill_buf()?;
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(b"BZh") {
+        Box::new(BufReader::new(MultiBzDecoder::new(reader)))
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(BufReader::new(ZstdDecoder::with_buffer(reader)?))
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// Iterator over the lines of a reader, without their line endings (LF or CRLF).
+///
+/// Lines are split on bytes, so lines that are not valid UTF-8 are kept as they are. The lines end
+/// at the first read error, which is passed on.
+fn lines(reader: Box<dyn BufRead>) -> Box<dyn Iterator<Item = Result<Vec<u8>>>> {
+    let mut failed = false;
+    Box::new(reader.split(b'\n').map_while(move |line| {
+        if failed {
+            return None;
+        }
+        let mut line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                failed = true;
+                return Some(Err(err.into()));
+            }
+        };
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Some(Ok(line))
+    }))
+}
+
+/// Reads the payloads from stdin, one per line, so they can be piped from other tools.
+///
+/// Stdin can only be read once, so the number of payloads is never known up front.
+pub struct Stdin;
+
+impl PayloadSource for Stdin {
+    fn payloads(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>>>> {
+        Ok(Box::new(
+            lines(decompress(std::io::stdin().lock())?)
+                .map(|line| line.context("Could not read stdin")),
+        ))
+    }
+
+    fn count(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// Bloom filter for removing duplicate payloads in bounded memory
+///
+/// Since it is probabilistic, a unique payload is dropped every now and then, how often depends on
+/// how full the filter is.
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    const HASHES: u64 = 4;
+
+    fn new(bytes: usize) -> Self {
+        Self {
+            bits: vec![0; (bytes / 8).max(1)],
+        }
+    }
+
+    /// Inserts a payload, returns whether it was (probably) inserted before.
+    fn insert(&mut self, payload: &[u8]) -> bool {
+        let (mut h1, mut h2) = (DefaultHasher::new(), DefaultHasher::new());
+        payload.hash(&mut h1);
+        (payload, 1u8).hash(&mut h2);
+        let (h1, h2) = (h1.finish(), h2.finish() | 1);
+
+        let nbits = self.bits.len() as u64 * 64;
+        let mut seen = true;
+        for i in 0..Self::HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % nbits;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            if self.bits[word] & mask == 0 {
+                seen = false;
+                self.bits[word] |= mask;
+            }
+        }
+        seen
+    }
+}
+
+/// Drops the payloads that were already seen, using at most `bytes` of memory. Errors are kept.
+pub fn dedupe<T>(payloads: T, bytes: usize) -> impl Iterator<Item = Result<Vec<u8>>>
+where
+    T: Iterator<Item = Result<Vec<u8>>>,
+{
+    let mut filter = BloomFilter::new(bytes);
+    payloads.filter_ok(move |payload| !filter.insert(payload))
+}
+
+/// Generates every number in a range, from `from` to `to` (inclusive).
+///
+/// Parsed from `FROM-TO[,step=N][,width=N][,hex]`, the width defaults to the length of `FROM`,
+/// so `0000-9999` generates `0000`, `0001`, ..., `9999`.
+#[derive(Clone, Debug)]
+pub struct Numbers {
+    from: u64,
+    to: u64,
+    step: u64,
+    width: usize,
+    hex: bool,
+}
+
+impl Numbers {
+    pub fn count(&self) -> u64 {
+        (self.to - self.from) / self.step + 1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = String> {
+        let (width, hex) = (self.width, self.hex);
+        (self.from..=self.to)
+            .step_by(self.step as usize)
+            .map(move |n| {
+                if hex {
+                    format!("{:0width$x}", n, width = width)
+                } else {
+                    format!("{:0width$}", n, width = width)
+                }
+            })
+    }
+}
+
+impl FromStr for Numbers {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut opts = spec.split(',');
+        let range = opts.next().ok_or(anyhow!("Missing range"))?;
+        let (from, to) = range
+            .split_once('-')
+            .ok_or(anyhow!("Invalid range: {}", range))?;
+
+        let (mut step, mut width, mut hex) = (1, from.len(), false);
+        for opt in opts {
+            match opt.split_once('=') {
+                Some(("step", n)) => step = n.parse()?,
+                Some(("width", n)) => width = n.parse()?,
+                None if opt == "hex" => hex = true,
+                _ => return Err(anyhow!("Unknown option: {}", opt)),
+            }
+        }
+
+        let radix = if hex { 16 } else { 10 };
+        let (from, to) = (
+            u64::from_str_radix(from, radix)?,
+            u64::from_str_radix(to, radix)?,
+        );
+        if from > to {
+            return Err(anyhow!("Invalid range: {}", range));
+        }
+        if step == 0 {
+            return Err(anyhow!("Step must be greater than zero"));
+        }
+        // The count has to fit, see `count`
+        ((to - from) / step)
+            .checked_add(1)
+            .ok_or(anyhow!("Too many numbers in range: {}", range))?;
+
+        Ok(Self {
+            from,
+            to,
+            step,
+            width,
+            hex,
+        })
+    }
+}
+
+/// Generates every string over a charset with a length between `min` and `max`.
+///
+/// Parsed from `charset=CHARS,min=N,max=N[,start=N][,end=N]`, where `CHARS` may contain ranges
+/// such as `a-z0-9`. Strings are generated shortest first, `start` and `end` (exclusive) select
+/// a slice of the keyspace by index so that large keyspaces can be split across runs.
+#[derive(Clone, Debug)]
+pub struct Bruteforce {
+    charset: Vec<char>,
+    min: u32,
+    max: u32,
+    start: u64,
+    end: Option<u64>,
+}
+
+impl Bruteforce {
+    /// Total number of strings over the charset, ignoring `start` and `end`.
+    pub fn keyspace(&self) -> Result<u64> {
+        let n = self.charset.len() as u64;
+        (self.min..=self.max).try_fold(0u64, |total, len| {
+            n.checked_pow(len)
+                .and_then(|count| total.checked_add(count))
+                .ok_or(anyhow!("Keyspace too large"))
+        })
+    }
+
+    /// Number of strings between `start` and `end`.
+    pub fn count(&self) -> u64 {
+        // The keyspace was validated when parsing
+        self.end.unwrap_or(self.keyspace().unwrap()) - self.start
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = String> {
+        let end = self.start + self.count();
+        let n = self.charset.len() as u64;
+
+        // Find the length and position of the string at index start
+        let (mut len, mut offset) = (self.min, self.start);
+        while len <= self.max && offset >= n.pow(len) {
+            offset -= n.pow(len);
+            len += 1;
+        }
+        let mut digits = vec![0; len as usize];
+        for digit in digits.iter_mut().rev() {
+            *digit = (offset % n) as usize;
+            offset /= n;
+        }
+
+        BruteforceIter {
+            charset: self.charset.clone(),
+            digits,
+            remaining: end - self.start,
+        }
+    }
+
+    /// Expands ranges such as `a-z` into their characters, a `-` at either end is literal.
+    fn parse_charset(spec: &str) -> Result<Vec<char>> {
+        let chars: Vec<char> = spec.chars().collect();
+        let mut charset = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                if chars[i] > chars[i + 2] {
+                    return Err(anyhow!("Invalid charset range: {}-{}", chars[i], chars[i + 2]));
+                }
+                charset.extend(chars[i]..=chars[i + 2]);
+                i += 3;
+            } else {
+                charset.push(chars[i]);
+                i += 1;
+            }
+        }
+        Ok(charset.into_iter().unique().collect())
+    }
+}
+
+/// Iterator over a [Bruteforce] keyspace, counting in base `charset.len()`.
+struct BruteforceIter {
+    charset: Vec<char>,
+    digits: Vec<usize>,
+    remaining: u64,
+}
+
+impl Iterator for BruteforceIter {
+    type Item = String;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let out = self.digits.iter().map(|&d| self.charset[d]).collect();
+
+        // Increment, growing to the next length once every digit wraps around
+        for digit in self.digits.iter_mut().rev() {
+            *digit += 1;
+            if *digit < self.charset.len() {
+                return Some(out);
+            }
+            *digit = 0;
+        }
+        self.digits.push(0);
+        Some(out)
+    }
+}
+
+impl FromStr for Bruteforce {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (mut charset, mut min, mut max, mut start, mut end) = (None, 1, None, 0, None);
+        for opt in spec.split(',') {
+            match opt.split_once('=') {
+                Some(("charset", chars)) => charset = Some(Self::parse_charset(chars)?),
+                Some(("min", n)) => min = n.parse()?,
+                Some(("max", n)) => max = Some(n.parse()?),
+                Some(("start", n)) => start = n.parse()?,
+                Some(("end", n)) => end = Some(n.parse()?),
+                _ => return Err(anyhow!("Unknown option: {}", opt)),
+            }
+        }
+
+        let charset = charset
+            .filter(|c| !c.is_empty())
+            .ok_or(anyhow!("Missing charset"))?;
+        let max = max.ok_or(anyhow!("Missing max"))?;
+        if min > max {
+            return Err(anyhow!("min must not be greater than max"));
+        }
+
+        let bruteforce = Self {
+            charset,
+            min,
+            max,
+            start,
+            end,
+        };
+        let keyspace = bruteforce.keyspace()?;
+        if start > keyspace || end.is_some_and(|end| end > keyspace || end < start) {
+            return Err(anyhow!("start and end must be within the keyspace (0-{})", keyspace));
+        }
+        Ok(bruteforce)
+    }
+}
+
+/// The interval between two generated dates.
+#[derive(Copy, Clone, Debug)]
+enum DateStep {
+    Days(u32),
+    Months(u32),
+}
+
+impl FromStr for DateStep {
+    type Err = Error;
+    fn from_str(step: &str) -> Result<Self, Self::Err> {
+        let unit = step.chars().last().ok_or(anyhow!("Empty step"))?;
+        let n: u32 = step[..step.len() - unit.len_utf8()].parse()?;
+        if n == 0 {
+            return Err(anyhow!("Step must be greater than zero"));
+        }
+        match unit {
+            'd' => Ok(Self::Days(n)),
+            'm' => Ok(Self::Months(n)),
+            'y' => Ok(Self::Months(
+                n.checked_mul(12).ok_or(anyhow!("Step too large: {}", step))?,
+            )),
+            _ => Err(anyhow!("Unknown step unit: {}", unit)),
+        }
+    }
+}
+
+/// Generates every date from `start` to `end` (inclusive), formatted with a strftime-style format.
+///
+/// Parsed from `START..END[:FORMAT][:STEP]`, where `START` and `END` are `YYYY-MM-DD` dates,
+/// `FORMAT` defaults to `%Y-%m-%d` and `STEP` is a number followed by `d`, `m` or `y`
+/// (defaults to `1d`).
+#[derive(Clone, Debug)]
+pub struct Dates {
+    start: NaiveDate,
+    end: NaiveDate,
+    step: DateStep,
+    format: String,
+}
+
+impl Dates {
+    pub fn count(&self) -> u64 {
+        match self.step {
+            DateStep::Days(n) => (self.end - self.start).num_days() as u64 / u64::from(n) + 1,
+            DateStep::Months(n) => {
+                let mut months = ((self.end.year() - self.start.year()) * 12
+                    + self.end.month() as i32
+                    - self.start.month() as i32) as u32;
+                // The last month only counts if its (clamped) day was reached
+                if self.start.checked_add_months(Months::new(months)) > Some(self.end) {
+                    months -= 1;
+                }
+                u64::from(months / n) + 1
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = String> {
+        let (start, end, step, format) = (self.start, self.end, self.step, self.format.clone());
+        // Every date is computed from start, so month steps don't drift after a short month
+        (0u32..)
+            .map_while(move |i| match step {
+                DateStep::Days(n) => start.checked_add_days(Days::new(u64::from(i) * u64::from(n))),
+                DateStep::Months(n) => start.checked_add_months(Months::new(i.checked_mul(n)?)),
+            })
+            .take_while(move |date| *date <= end)
+            .map(move |date| date.format(&format).to_string())
+    }
+}
+
+impl FromStr for Dates {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (range, rest) = spec.split_once(':').unwrap_or((spec, ""));
+        let (start, end) = range
+            .split_once("..")
+            .ok_or(anyhow!("Invalid range: {}", range))?;
+        let (start, end) = (
+            NaiveDate::parse_from_str(start, "%Y-%m-%d")?,
+            NaiveDate::parse_from_str(end, "%Y-%m-%d")?,
+        );
+        if start > end {
+            return Err(anyhow!("Invalid range: {}", range));
+        }
+
+        // The format may itself contain ':', so the step is only split off if it looks like one
+        let is_step = |s: &str| {
+            s.len() > 1
+                && s.ends_with(['d', 'm', 'y'])
+                && s[..s.len() - 1].bytes().all(|b| b.is_ascii_digit())
+        };
+        let (format, step) = match rest.rsplit_once(':') {
+            Some((format, step)) if is_step(step) => (format, step.parse()?),
+            _ if is_step(rest) => ("", rest.parse()?),
+            _ => (rest, DateStep::Days(1)),
+        };
+        let format = if format.is_empty() { "%Y-%m-%d" } else { format };
+        // Formatting fails on invalid specifiers, and on the time and offset ones a date lacks
+        let mut sample = String::new();
+        write!(sample, "{}", start.format_with_items(StrftimeItems::new(format)))
+            .map_err(|_| anyhow!("Invalid date format: {}", format))?;
+
+        Ok(Self {
+            start,
+            end,
+            step,
+            format: format.to_string(),
+        })
+    }
+}
+
+/// Generates every string matching a hashcat-style mask.
+///
+/// Parsed from `[-1 CHARSET] [-2 CHARSET] [-3 CHARSET] [-4 CHARSET] MASK`, so `?u?l?l?l?d?d`,
+/// `Summer20?d?d` and `-1 ?l?d ?1?1?1` are all valid. The builtin charsets are `?l`, `?u`, `?d`,
+/// `?h`, `?H`, `?s` and `?a`, `??` is a literal `?`.
+#[derive(Clone, Debug)]
+pub struct Mask {
+    positions: Vec<Vec<char>>,
+}
+
+impl Mask {
+    const SPECIAL: &'static str = " !\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
+
+    /// Number of strings matching the mask.
+    pub fn keyspace(&self) -> Result<u64> {
+        self.positions.iter().try_fold(1u64, |total, chars| {
+            total
+                .checked_mul(chars.len() as u64)
+                .ok_or(anyhow!("Keyspace too large"))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = String> {
+        MaskIter {
+            positions: self.positions.clone(),
+            digits: vec![0; self.positions.len()],
+            done: false,
+        }
+    }
+
+    /// Expands a charset definition, which may reference builtin and previously defined custom
+    /// charsets, into its characters.
+    fn parse_charset(spec: &str, custom: &[Option<Vec<char>>]) -> Result<Vec<char>> {
+        let mut charset = Vec::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '?' {
+                charset.push(c);
+                continue;
+            }
+            let class = chars.next().ok_or(anyhow!("Mask ends with a lone ?"))?;
+            match class {
+                'l' => charset.extend('a'..='z'),
+                'u' => charset.extend('A'..='Z'),
+                'd' => charset.extend('0'..='9'),
+                'h' => charset.extend(('0'..='9').chain('a'..='f')),
+                'H' => charset.extend(('0'..='9').chain('A'..='F')),
+                's' => charset.extend(Self::SPECIAL.chars()),
+                'a' => charset.extend(Self::parse_charset("?l?u?d?s", custom)?),
+                '?' => charset.push('?'),
+                '1'..='4' => charset.extend(
+                    custom[class as usize - '1' as usize]
+                        .clone()
+                        .ok_or(anyhow!("Custom charset ?{} is not defined", class))?,
+                ),
+                _ => return Err(anyhow!("Unknown charset: ?{}", class)),
+            }
+        }
+        Ok(charset.into_iter().unique().collect())
+    }
+}
+
+/// Iterator over a [Mask] keyspace, counting with one digit per position.
+struct MaskIter {
+    positions: Vec<Vec<char>>,
+    digits: Vec<usize>,
+    done: bool,
+}
+
+impl Iterator for MaskIter {
+    type Item = String;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let out = self
+            .digits
+            .iter()
+            .zip(&self.positions)
+            .map(|(&d, chars)| chars[d])
+            .collect();
+
+        self.done = true;
+        for (digit, chars) in self.digits.iter_mut().zip(&self.positions).rev() {
+            *digit += 1;
+            if *digit < chars.len() {
+                self.done = false;
+                break;
+            }
+            *digit = 0;
+        }
+        Some(out)
+    }
+}
+
+impl FromStr for Mask {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut custom = vec![None; 4];
+        let mut mask = spec;
+        while let Some(rest) = mask.strip_prefix('-') {
+            let (n, rest) = rest
+                .split_once(' ')
+                .ok_or(anyhow!("Invalid custom charset: {}", mask))?;
+            let (charset, rest) = rest.split_once(' ').ok_or(anyhow!("Missing mask"))?;
+            let n = match n {
+                "1" | "2" | "3" | "4" => n.parse::<usize>()? - 1,
+                _ => return Err(anyhow!("Unknown custom charset: -{}", n)),
+            };
+            custom[n] = Some(Self::parse_charset(charset, &custom)?);
+            mask = rest;
+        }
+
+        let mut positions = Vec::new();
+        let mut chars = mask.chars();
+        while let Some(c) = chars.next() {
+            let class = match c {
+                '?' => format!("?{}", chars.next().ok_or(anyhow!("Mask ends with a lone ?"))?),
+                _ => c.to_string(),
+            };
+            positions.push(Self::parse_charset(&class, &custom)?);
+        }
+        if positions.iter().any(Vec::is_empty) {
+            return Err(anyhow!("Mask has an empty charset"));
+        }
+
+        let mask = Self { positions };
+        mask.keyspace()?;
+        Ok(mask)
+    }
+}
+
+/// One component of a username format.
+#[derive(Clone, Debug)]
+enum NamePart {
+    First,
+    Last,
+    FirstInitial,
+    LastInitial,
+    Separator(char),
+}
+
+/// Generates likely usernames from a file of "First Last" lines.
+///
+/// Parsed from `FILE[,formats=FORMAT+FORMAT...][,domain=DOMAIN]`, where a format is made of
+/// `first`, `last`, `f`, `l` and the separators `.`, `_` and `-`, for example `flast` or
+/// `first.last`. Names are lowercased, and lines with a single name are skipped.
+#[derive(Clone, Debug)]
+pub struct Names {
+    path: PathBuf,
+    formats: Arc<Vec<Vec<NamePart>>>,
+    domain: Option<String>,
+}
+
+impl Names {
+    const DEFAULT_FORMATS: &'static str = "flast+first.last+lastf+first_last+f.last";
+
+    fn parse_format(format: &str) -> Result<Vec<NamePart>> {
+        if format.is_empty() {
+            return Err(anyhow!("Empty username format"));
+        }
+        let mut parts = Vec::new();
+        let mut rest = format;
+        while !rest.is_empty() {
+            let (part, len) = if rest.starts_with("first") {
+                (NamePart::First, 5)
+            } else if rest.starts_with("last") {
+                (NamePart::Last, 4)
+            } else if rest.starts_with('f') {
+                (NamePart::FirstInitial, 1)
+            } else if rest.starts_with('l') {
+                (NamePart::LastInitial, 1)
+            } else if rest.starts_with(['.', '_', '-']) {
+                (NamePart::Separator(rest.chars().next().unwrap()), 1)
+            } else {
+                return Err(anyhow!("Invalid username format: {}", format));
+            };
+            parts.push(part);
+            rest = &rest[len..];
+        }
+        Ok(parts)
+    }
+
+    /// First and last name of every line that has both
+    fn names(&self) -> Result<impl Iterator<Item = Result<(String, String)>>> {
+        Ok(lines(Box::new(BufReader::new(File::open(&self.path)?)))
+            .filter_map_ok(|line| {
+                let line = String::from_utf8_lossy(&line);
+                let mut words = line.split_whitespace().map(str::to_lowercase);
+                let first = words.next()?;
+                let last = words.next_back()?;
+                Some((first, last))
+            }))
+    }
+
+    pub fn count(&self) -> Result<u64> {
+        let names = self.names()?.fold_ok(0, |count, _| count + 1)?;
+        Ok(names * self.formats.len() as u64)
+    }
+
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<String>>> {
+        let (formats, domain) = (self.formats.clone(), self.domain.clone());
+        Ok(self.names()?.map_ok(move |(first, last)| {
+            let (formats, domain) = (formats.clone(), domain.clone());
+            (0..formats.len()).map(move |i| {
+                let mut username: String = formats[i]
+                    .iter()
+                    .map(|part| match part {
+                        NamePart::First => first.clone(),
+                        NamePart::Last => last.clone(),
+                        NamePart::FirstInitial => first.chars().take(1).collect(),
+                        NamePart::LastInitial => last.chars().take(1).collect(),
+                        NamePart::Separator(c) => c.to_string(),
+                    })
+                    .collect();
+                if let Some(domain) = &domain {
+                    username.push('@');
+                    username.push_str(domain);
+                }
+                username
+            })
+        })
+        .flatten_ok())
+    }
+}
+
+impl FromStr for Names {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut opts = spec.split(',');
+        let path = PathBuf::from(opts.next().ok_or(anyhow!("Missing names file"))?);
+        let (mut formats, mut domain) = (Self::DEFAULT_FORMATS, None);
+        for opt in opts {
+            match opt.split_once('=') {
+                Some(("formats", f)) => formats = f,
+                Some(("domain", d)) => domain = Some(d.to_string()),
+                _ => return Err(anyhow!("Unknown option: {}", opt)),
+            }
+        }
+        if !path.is_file() {
+            return Err(anyhow!("Names file not found: {}", path.display()));
+        }
+
+        Ok(Self {
+            path,
+            formats: Arc::new(formats.split('+').map(Self::parse_format).try_collect()?),
+            domain,
+        })
+    }
+}
+
+/// Reads the payloads from the CSV or JSONL output of a previous run.
+///
+/// Parsed from `FILE[,field=FIELD][,status=CODE+CODE...]`, `FIELD` is one of the CSV columns
+/// (`Index`, `Payload` and `Status`), a JSON key or a JSON pointer such as `/Body`, and defaults
+/// to `Payload`. Only hits are written to the output, and `status` narrows them down further.
+/// Reading fails if no line of the file has the field, e.g. when it is misspelled.
+#[derive(Clone, Debug)]
+pub struct Results {
+    path: PathBuf,
+    field: String,
+    status: Vec<u16>,
+}
+
+impl Results {
+    /// Extracts the field and the status out of an output line
+    ///
+    /// JSON fields written in base64 (flagged by a `<FIELD>Encoding` key) are decoded.
+    fn record(line: &str, field: &str) -> Option<(Vec<u8>, u16)> {
+        if line.starts_with('{') {
+            let json: Value = serde_json::from_str(line).ok()?;
+            let status = json.get("Status")?.as_u64()? as u16;
+            let value = if field.starts_with('/') {
+                json.pointer(field)?
+            } else {
+                json.get(field)?
+            };
+            let encoding = json
+                .get(format!("{}Encoding", field.trim_start_matches('/')))
+                .and_then(Value::as_str);
+            let value = match value {
+                Value::String(value) if encoding == Some("base64") => STANDARD.decode(value).ok()?,
+                Value::String(value) => value.clone().into_bytes(),
+                value => value.to_string().into_bytes(),
+            };
+            return Some((value, status));
+        }
+
+        // The payload may itself contain ", ", so the outer columns are split off first
+        let (index, rest) = line.split_once(", ")?;
+        let (payload, status) = rest.rsplit_once(", ")?;
+        let status: u16 = status.split(' ').next()?.parse().ok()?;
+        let value = match field {
+            "Index" => index.to_string(),
+            "Payload" => payload.to_string(),
+            "Status" => status.to_string(),
+            _ => return None,
+        };
+        Some((value.into_bytes(), status))
+    }
+
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<Vec<u8>>>> {
+        let (path, field, status) = (self.path.clone(), self.field.clone(), self.status.clone());
+        let mut lines = lines(Box::new(BufReader::new(File::open(&self.path)?)));
+        // Whether there were any lines, and whether any of them had the field
+        let (mut seen, mut found) = (false, false);
+        Ok(std::iter::from_fn(move || loop {
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(err)) => return Some(Err(err)),
+                None if found || !seen => return None,
+                None => {
+                    found = true;
+                    let err = anyhow!("No line of {} has the field {}", path.display(), field);
+                    return Some(Err(err));
+                }
+            };
+            if line.is_empty() {
+                continue;
+            }
+            seen = true;
+            if let Some((value, code)) = Self::record(&String::from_utf8_lossy(&line), &field) {
+                found = true;
+                if status.is_empty() || status.contains(&code) {
+                    return Some(Ok(value));
+                }
+            }
+        }))
+    }
+
+    pub fn count(&self) -> Result<u64> {
+        self.iter()?.fold_ok(0, |count, _| count + 1)
+    }
+}
+
+impl FromStr for Results {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut opts = spec.split(',');
+        let path = PathBuf::from(opts.next().ok_or(anyhow!("Missing results file"))?);
+        let (mut field, mut status) = ("Payload".to_string(), Vec::new());
+        for opt in opts {
+            match opt.split_once('=') {
+                Some(("field", f)) => field = f.to_string(),
+                Some(("status", codes)) => {
+                    status = codes.split('+').map(str::parse).try_collect()?
+                }
+                _ => return Err(anyhow!("Unknown option: {}", opt)),
+            }
+        }
+        if !path.is_file() {
+            return Err(anyhow!("Results file not found: {}", path.display()));
+        }
+
+        Ok(Self {
+            path,
+            field,
+            status,
+        })
+    }
+}
+
+/// Reads the payloads from the files in a directory, the contents of each file being one payload,
+/// for fuzzing upload filters with many file types.
+///
+/// Parsed from `DIR[,ext=EXT+EXT...]`, the files are read in name order and subdirectories are
+/// not entered. Files that can't be read are skipped.
+#[derive(Clone, Debug)]
+pub struct Files {
+    dir: PathBuf,
+    ext: Vec<String>,
+}
+
+impl Files {
+    fn paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .try_collect()?;
+        paths.retain(|path| {
+            let ext = path.extension().and_then(|ext| ext.to_str());
+            path.is_file()
+                && (self.ext.is_empty()
+                    || ext.is_some_and(|ext| self.ext.iter().any(|e| e.eq_ignore_ascii_case(ext))))
+        });
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub fn iter(&self) -> Result<impl Iterator<Item = Vec<u8>>> {
+        Ok(self
+            .paths()?
+            .into_iter()
+            .filter_map(|path| fs::read(path).ok()))
+    }
+
+    pub fn count(&self) -> Result<u64> {
+        Ok(self.iter()?.count() as u64)
+    }
+}
+
+impl FromStr for Files {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut opts = spec.split(',');
+        let dir = PathBuf::from(opts.next().ok_or(anyhow!("Missing directory"))?);
+        let mut ext = Vec::new();
+        for opt in opts {
+            match opt.split_once('=') {
+                Some(("ext", exts)) => {
+                    ext = exts
+                        .split('+')
+                        .map(|e| e.trim_start_matches('.').to_string())
+                        .collect()
+                }
+                _ => return Err(anyhow!("Unknown option: {}", opt)),
+            }
+        }
+        if !dir.is_dir() {
+            return Err(anyhow!("Directory not found: {}", dir.display()));
+        }
+
+        Ok(Self { dir, ext })
+    }
+}
+
+/// Sends the template unchanged, either a number of times or forever (until the attack is stopped).
+///
+/// Parsed from `COUNT` or `forever`. The payloads are the sequence indexes of the requests, starting
+/// at 1, so that the responses can be told apart in the output.
+#[derive(Clone, Debug)]
+pub struct Null {
+    count: Option<u64>,
+}
+
+impl Null {
+    pub fn count(&self) -> Option<u64> {
+        self.count
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = String> {
+        let count = self.count;
+        (1..)
+            .take_while(move |&i| count.is_none_or(|count| i <= count))
+            .map(|i: u64| i.to_string())
+    }
+}
+
+impl FromStr for Null {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let count = match spec {
+            "forever" => None,
+            count => Some(count.parse()?),
+        };
+        Ok(Self { count })
+    }
+}
+
+/// A built-in payload generator, parsed from a `kind:spec` string.
+#[derive(Clone, Debug)]
+pub enum PayloadGenerator {
+    Numbers(Numbers),
+    Bruteforce(Bruteforce),
+    Dates(Dates),
+    Mask(Mask),
+    Names(Names),
+    Results(Results),
+    Files(Files),
+    Null(Null),
+}
+
+impl PayloadSource for PayloadGenerator {
+    fn payloads(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>>>> {
+        let bytes = |payload: String| Ok(payload.into_bytes());
+        Ok(match self {
+            Self::Numbers(numbers) => Box::new(numbers.iter().map(bytes)),
+            Self::Bruteforce(bruteforce) => Box::new(bruteforce.iter().map(bytes)),
+            Self::Dates(dates) => Box::new(dates.iter().map(bytes)),
+            Self::Mask(mask) => Box::new(mask.iter().map(bytes)),
+            Self::Names(names) => Box::new(names.iter()?.map_ok(String::into_bytes)),
+            Self::Results(results) => Box::new(results.iter()?),
+            Self::Files(files) => Box::new(files.iter()?.map(Ok)),
+            Self::Null(null) => Box::new(null.iter().map(bytes)),
+        })
+    }
+
+    fn count(&self) -> Result<Option<u64>> {
+        Ok(match self {
+            Self::Numbers(numbers) => Some(numbers.count()),
+            Self::Bruteforce(bruteforce) => Some(bruteforce.count()),
+            Self::Dates(dates) => Some(dates.count()),
+            // The keyspace was validated when parsing
+            Self::Mask(mask) => Some(mask.keyspace().unwrap()),
+            Self::Names(names) => Some(names.count()?),
+            Self::Results(results) => Some(results.count()?),
+            Self::Files(files) => Some(files.count()?),
+            Self::Null(null) => null.count(),
+        })
+    }
+
+    fn substitutes(&self) -> bool {
+        !matches!(self, Self::Null(_))
+    }
+}
+
+impl FromStr for PayloadGenerator {
+    type Err = Error;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (kind, spec) = spec
+            .split_once(':')
+            .ok_or(anyhow!("Expected <kind>:<spec>"))?;
+        match kind {
+            "numbers" => Ok(Self::Numbers(spec.parse()?)),
+            "bruteforce" => Ok(Self::Bruteforce(spec.parse()?)),
+            "dates" => Ok(Self::Dates(spec.parse()?)),
+            "mask" => Ok(Self::Mask(spec.parse()?)),
+            "names" => Ok(Self::Names(spec.parse()?)),
+            "results" => Ok(Self::Results(spec.parse()?)),
+            "files" => Ok(Self::Files(spec.parse()?)),
+            "null" => Ok(Self::Null(spec.parse()?)),
+            _ => Err(anyhow!("Unknown payload generator: {}", kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wordlists_pass_on_read_errors() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        for i in 0..200_000 {
+            writeln!(gz, "{}", i).unwrap();
+        }
+        let gz = gz.finish().unwrap();
+        let complete = temp_file("complete.gz", &gz);
+        let truncated = temp_file("truncated.gz", &gz[..gz.len() / 2]);
+
+        let wordlist = Wordlist::new(complete.clone());
+        assert_eq!(wordlist.count().unwrap(), Some(200_000));
+        let payloads: Vec<_> = wordlist.payloads().unwrap().try_collect().unwrap();
+        assert_eq!(payloads.last().unwrap(), b"199999");
+
+        let wordlist = Wordlist::new(truncated.clone());
+        let payloads: Vec<_> = wordlist.payloads().unwrap().collect();
+        let (last, read) = payloads.split_last().unwrap();
+        assert!(read.len() < 200_000 && read.iter().all(Result::is_ok));
+        let err = last.as_ref().unwrap_err().to_string();
+        assert!(err.starts_with("Could not read "), "{}", err);
+        assert!(wordlist.count().is_err());
+        fs::remove_file(complete).unwrap();
+        fs::remove_file(truncated).unwrap();
+    }
+
+    #[test]
+    fn dedupe_keeps_the_first_of_each_payload() {
+        let payloads = ["a", "b", "a", "c", "b", "", ""].map(|p| p.as_bytes().to_vec());
+        let unique: Vec<_> = dedupe(payloads.into_iter().map(Ok), 1024)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(unique, ["a", "b", "c", ""].map(|p| p.as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn dedupe_skips_unique_payloads_once_the_filter_fills_up() {
+        let numbers = || (0..10_000).map(|n| Ok(n.to_string().into_bytes()));
+        assert_eq!(dedupe(numbers(), 40_000).count(), 10_000);
+        // 64 bits for 10000 payloads, nearly every one is taken for a duplicate
+        assert!(dedupe(numbers(), 8).count() < 100);
+    }
+
+    fn numbers(spec: &str) -> Vec<String> {
+        let numbers = spec.parse::<Numbers>().unwrap();
+        let words: Vec<_> = numbers.iter().collect();
+        assert_eq!(numbers.count(), words.len() as u64, "{}", spec);
+        words
+    }
+
+    #[test]
+    fn numbers_are_padded_to_the_width_of_from() {
+        assert_eq!(numbers("8-11"), ["8", "9", "10", "11"]);
+        assert_eq!(numbers("008-011")[..3], ["008", "009", "010"]);
+        assert_eq!(numbers("9998-10000"), ["9998", "9999", "10000"]);
+        assert_eq!(numbers("0-2,width=3"), ["000", "001", "002"]);
+        assert_eq!(numbers("5-5"), ["5"]);
+    }
+
+    #[test]
+    fn numbers_options() {
+        assert_eq!(numbers("0-10,step=4"), ["0", "4", "8"]);
+        assert_eq!(numbers("0-12,step=4"), ["0", "4", "8", "12"]);
+        assert_eq!(numbers("fe-101,hex"), ["fe", "ff", "100", "101"]);
+        assert_eq!(numbers("00-10,hex,step=8"), ["00", "08", "10"]);
+        assert_eq!(numbers("a-b,hex,width=4"), ["000a", "000b"]);
+    }
+
+    #[test]
+    fn numbers_reject_invalid_specs() {
+        assert!("10-9".parse::<Numbers>().is_err());
+        assert!("0-9,step=0".parse::<Numbers>().is_err());
+        assert!("0-9,step=-1".parse::<Numbers>().is_err());
+        assert!("0-ff".parse::<Numbers>().is_err());
+        assert!("0-9,odd".parse::<Numbers>().is_err());
+        assert!("0..9".parse::<Numbers>().is_err());
+        // The count of a full range doesn't fit
+        assert!("0-18446744073709551615".parse::<Numbers>().is_err());
+        assert!("0-ffffffffffffffff,hex".parse::<Numbers>().is_err());
+        let numbers = "0-18446744073709551615,step=2".parse::<Numbers>().unwrap();
+        assert_eq!(numbers.count(), 1 << 63);
+    }
+
+    fn bruteforce(spec: &str) -> Vec<String> {
+        spec.parse::<Bruteforce>().unwrap().iter().collect()
+    }
+
+    #[test]
+    fn bruteforce_keyspace_is_shortest_first() {
+        let words = bruteforce("charset=ab,min=1,max=3");
+        assert_eq!(words.len(), 2 + 4 + 8);
+        assert_eq!(&words[..7], ["a", "b", "aa", "ab", "ba", "bb", "aaa"]);
+        assert_eq!(words.last().unwrap(), "bbb");
+        assert_eq!(words.iter().unique().count(), words.len());
+    }
+
+    #[test]
+    fn bruteforce_shards_concatenate_to_the_keyspace() {
+        let full = bruteforce("charset=a-c,min=1,max=3");
+        // 2 and 3 fall on the boundary between lengths 1 and 2, 12 on the one between 2 and 3
+        let bounds = [0, 2, 3, 7, 11, 12, 13, full.len()];
+        let mut joined = Vec::new();
+        for w in bounds.windows(2) {
+            let spec = format!("charset=a-c,min=1,max=3,start={},end={}", w[0], w[1]);
+            let shard = bruteforce(&spec);
+            assert_eq!(shard.len() as u64, spec.parse::<Bruteforce>().unwrap().count());
+            joined.extend(shard);
+        }
+        assert_eq!(joined, full);
+    }
+
+    #[test]
+    fn bruteforce_start_without_end_runs_to_the_end() {
+        let full = bruteforce("charset=01,min=2,max=4");
+        assert_eq!(bruteforce("charset=01,min=2,max=4,start=5"), full[5..]);
+        assert!(bruteforce("charset=01,min=2,max=4,start=28").is_empty());
+    }
+
+    #[test]
+    fn bruteforce_rejects_invalid_specs() {
+        assert!("charset=ab,min=1,max=2,start=7".parse::<Bruteforce>().is_err());
+        assert!("charset=ab,min=1,max=2,start=3,end=2".parse::<Bruteforce>().is_err());
+        assert!("charset=ab,min=3,max=2".parse::<Bruteforce>().is_err());
+        assert!("charset=a-z,min=1,max=20".parse::<Bruteforce>().is_err());
+        assert!("charset=z-a,min=1,max=2".parse::<Bruteforce>().is_err());
+    }
+
+    #[test]
+    fn bruteforce_charset_ranges() {
+        assert_eq!(
+            Bruteforce::parse_charset("a-c0-2-").unwrap(),
+            ['a', 'b', 'c', '0', '1', '2', '-']
+        );
+        assert_eq!(Bruteforce::parse_charset("-ab").unwrap(), ['-', 'a', 'b']);
+    }
+
+    #[test]
+    fn dates_reject_formats_a_date_cannot_fill() {
+        assert!("2020-01-01..2020-01-02:%Y%H".parse::<Dates>().is_err());
+        assert!("2020-01-01..2020-01-02:%d %z".parse::<Dates>().is_err());
+        assert!("2020-01-01..2020-01-02:%Q".parse::<Dates>().is_err());
+        let dates = "2020-01-30..2020-02-01:%d/%m".parse::<Dates>().unwrap();
+        assert_eq!(dates.iter().collect::<Vec<_>>(), ["30/01", "31/01", "01/02"]);
+    }
+
+    /// Writes a file for a test to read payloads from
+    fn temp_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let name = format!("rip-intruder-{}-{}", std::process::id(), name);
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn names(name: &str, lines: &str, opts: &str) -> Vec<String> {
+        let path = temp_file(name, lines.as_bytes());
+        let names = format!("{}{}", path.display(), opts).parse::<Names>().unwrap();
+        let usernames: Vec<_> = names.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(names.count().unwrap(), usernames.len() as u64);
+        fs::remove_file(path).unwrap();
+        usernames
+    }
+
+    #[test]
+    fn names_formats() {
+        let formats = ",formats=flast+lastf+first.last+f.l+first_l-f";
+        assert_eq!(
+            names("names-formats", "John Smith\n", formats),
+            ["jsmith", "smithj", "john.smith", "j.s", "john_s-j"]
+        );
+        assert_eq!(
+            names("names-default", "Jane Doe\n", ""),
+            ["jdoe", "jane.doe", "doej", "jane_doe", "j.doe"]
+        );
+    }
+
+    #[test]
+    fn names_skip_middle_names_and_single_words() {
+        let lines = "Mary Ann Jones\r\nPrince\n\n  ALICE   O'Neil  \nÉmile Zola\n";
+        assert_eq!(
+            names("names-middle", lines, ",formats=first.last"),
+            ["mary.jones", "alice.o'neil", "émile.zola"]
+        );
+        assert_eq!(names("names-initials", lines, ",formats=fl"), ["mj", "ao", "éz"]);
+    }
+
+    #[test]
+    fn names_domain_suffix() {
+        assert_eq!(
+            names("names-domain", "John Smith\nJane Doe\n", ",formats=flast,domain=corp.example"),
+            ["jsmith@corp.example", "jdoe@corp.example"]
+        );
+    }
+
+    #[test]
+    fn names_reject_invalid_specs() {
+        let path = temp_file("names-invalid", b"John Smith\n");
+        for opts in [",formats=flast+fist", ",formats=", ",format=flast", ",formats=f l"] {
+            let spec = format!("{}{}", path.display(), opts);
+            assert!(spec.parse::<Names>().is_err(), "{}", opts);
+        }
+        assert!("/nonexistent/names.txt".parse::<Names>().is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    fn mask(spec: &str) -> (u64, Vec<String>) {
+        let mask = spec.parse::<Mask>().unwrap();
+        (mask.keyspace().unwrap(), mask.iter().collect())
+    }
+
+    #[test]
+    fn mask_keyspace_matches_iteration() {
+        for spec in ["?d", "?l?d", "Summer20?d?d", "?h?H", "?s", "a?u?a", "-1 ?l?d ?1?1"] {
+            let (keyspace, words) = mask(spec);
+            assert_eq!(keyspace, words.len() as u64, "{}", spec);
+            assert_eq!(words.iter().unique().count(), words.len(), "{}", spec);
+        }
+        assert_eq!(mask("?a").0, 95);
+    }
+
+    #[test]
+    fn mask_iterates_the_last_position_first() {
+        let (_, words) = mask("Summer20?d?d");
+        assert_eq!(&words[..3], ["Summer2000", "Summer2001", "Summer2002"]);
+        assert_eq!(words.last().unwrap(), "Summer2099");
+        assert_eq!(mask("x??").1, ["x?"]);
+    }
+
+    #[test]
+    fn mask_custom_charsets() {
+        let (keyspace, words) = mask("-1 ab -2 ?1c ?1?2");
+        assert_eq!(keyspace, 6);
+        assert_eq!(words, ["aa", "ab", "ac", "ba", "bb", "bc"]);
+        // Duplicates within a charset are only generated once
+        assert_eq!(mask("-1 ?daa0 ?1").0, 11);
+    }
+
+    #[test]
+    fn mask_rejects_invalid_specs() {
+        assert!("?3".parse::<Mask>().is_err());
+        assert!("-5 ab ?1".parse::<Mask>().is_err());
+        assert!("-1 ab".parse::<Mask>().is_err());
+        assert!("abc?".parse::<Mask>().is_err());
+        assert!("?x".parse::<Mask>().is_err());
+        assert!("?a".repeat(12).parse::<Mask>().is_err());
+    }
+}
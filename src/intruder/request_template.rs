@@ -6,13 +6,17 @@ use anyhow::{anyhow, Error, Result};
 use hyper::header::HeaderName;
 use hyper::http::header::HeaderValue;
 use hyper::http::request::Builder;
-use hyper::{Body, HeaderMap, Method, Request, Uri, Version};
+use hyper::http::uri::{Authority, Parts, PathAndQuery, Scheme};
+use hyper::{Body, Method, Request, Uri, Version};
 
 use itertools::Itertools;
+use regex::bytes::{NoExpand, Regex as BytesRegex};
 use regex::Regex;
 
 use std::fs::File;
-use std::io::{prelude::*, BufReader};
+use std::io::prelude::*;
+
+use crate::multipart::Multipart;
 
 #[derive(Copy, Clone, Debug)]
 pub enum AttackType {
@@ -22,15 +26,40 @@ pub enum AttackType {
     ClusterBomb
 }
 
+/// A header as it is written in the template, with its original casing
+///
+/// Kept as bytes, since the payloads placed into it need not be valid UTF-8.
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Headers of a request in the order and casing they are written in the template
+///
+/// Every request built from a template carries this as an extension. hyper lowercases the names
+/// and groups duplicates together, so the transports that write the request themselves use it
+/// to send the headers exactly as they are in the template.
+#[derive(Clone, Debug, Default)]
+pub struct OriginalHeaders(pub Vec<Header>);
+
+/// The request-target of a request as it is written in the template
+///
+/// Carried as an extension like [OriginalHeaders]. hyper always sends the origin-form (or the
+/// authority-form for `CONNECT`), while the absolute-form of the template is kept here.
+#[derive(Clone, Debug)]
+pub struct RequestTarget(pub Vec<u8>);
+
 /// Represents the components of a request for recreating the [Request] object
 ///
 /// This struct is useful since the [Request] object is not Clone.
 /// (TODO: Add Extensions)
 pub struct RequestComponents {
-    head: HeaderMap<HeaderValue>,
-    uri: Uri,
+    head: Vec<Header>,
+    /// The request-target from the request line
+    target: Vec<u8>,
     version: Version,
-    body: String,
+    body: Vec<u8>,
     method: Method,
 }
 
@@ -38,24 +67,61 @@ impl RequestComponents {
     /// Create a new empty [RequestComponents].
     fn new() -> Self {
         RequestComponents {
-            head: HeaderMap::new(),
-            uri: Uri::from_static("/"),
+            head: Vec::new(),
+            target: b"/".to_vec(),
             version: Version::HTTP_11,
-            body: "".to_string(),
+            body: Vec::new(),
             method: Method::GET,
         }
     }
 
-    /// Insert a header into head.
-    fn insert_header(&mut self, key: String, value: String) -> Result<()> {
-        self.head.insert(
-            <HeaderName as TryFrom<String>>::try_from(key)?,
-            <HeaderValue as TryFrom<String>>::try_from(value)?,
-        );
+    /// Insert a header into head, after the ones already there.
+    fn insert_header(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        HeaderName::from_bytes(key)?;
+        HeaderValue::from_bytes(value)?;
+        self.head.push(Header {
+            name: key.to_vec(),
+            value: value.to_vec(),
+        });
         Ok(())
     }
 }
 
+/// Whitespace allowed around a field value (OWS in RFC 9110)
+const OWS: [u8; 2] = [b' ', b'\t'];
+
+/// Strips OWS from the end of a field.
+fn trim_ows_end(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|b| !OWS.contains(b)).map_or(0, |end| end + 1);
+    &bytes[..end]
+}
+
+/// Strips OWS from both ends of a field value.
+fn trim_ows(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| !OWS.contains(b)).unwrap_or(bytes.len());
+    trim_ows_end(&bytes[start..])
+}
+
+/// Splits off the first line, returning it without its line ending (LF or CRLF) and the rest.
+fn split_line(bytes: &[u8]) -> (&[u8], &[u8]) {
+    let (line, rest) = match bytes.iter().position(|&b| b == b'\n') {
+        Some(pos) => (&bytes[..pos], &bytes[pos + 1..]),
+        None => (bytes, &[][..]),
+    };
+    (line.strip_suffix(b"\r").unwrap_or(line), rest)
+}
+
+/// Checks that a connection target is an absolute http(s) uri such as `https://host:port`.
+fn check_target(target: &Uri) -> Result<()> {
+    match (target.scheme_str(), target.authority()) {
+        (Some("http" | "https"), Some(_)) => Ok(()),
+        _ => Err(anyhow!(
+            "Invalid target {}, expected a uri such as https://host:port",
+            target
+        )),
+    }
+}
+
 /// Stores the template
 ///
 /// This struct stores the known RequestComponents, the pattern for identifying what components
@@ -65,13 +131,24 @@ pub struct RequestTemplate {
     pub req: RequestComponents,
     pub marked: Vec<Part>,
     pub pattern: Regex,
+    /// The pattern matched against bytes, for placing payloads that are not necessarily UTF-8
+    bytes_pattern: BytesRegex,
+    /// The parts of the body, when it is `multipart/form-data`
+    pub multipart: Option<Multipart>,
+    /// Connection target overriding the one from the template
+    target: Option<Uri>,
+    tls: bool,
     pub attack_type: AttackType
 }
 
 /// Either a element in the header is marked, or an element in the body.
+///
+/// Marked headers are kept in head with the others, so the index of the header is stored.
 pub enum Part {
-    Body(String),
-    Header(String),
+    Body,
+    Header(usize),
+    /// The request-target of the request line
+    Target,
 }
 
 /// Trait for creating a RequestTemplate from a file. (TODO: Implement TryFrom for other types)
@@ -79,66 +156,96 @@ impl TryFrom<ReqTemplateFile> for RequestTemplate {
     type Error = Error;
     fn try_from(req_templ: ReqTemplateFile) -> Result<Self, Self::Error> {
         let pattern = req_templ.pattern;
-        let req_file = req_templ.file;
-        let mut lines = BufReader::new(req_file).lines();
-        let request_line = lines.next().ok_or(anyhow!("File is empty"))??;
-        let (method, uri, httpver) = request_line
-            .split(' ')
+        let mut req_file = req_templ.file;
+        let mut bytes = Vec::new();
+        req_file.read_to_end(&mut bytes)?;
+        if bytes.is_empty() {
+            return Err(anyhow!("File is empty"));
+        }
+
+        let bytes_pattern = BytesRegex::new(pattern.as_str())?;
+
+        // The head ends at the first empty line, everything after it is the body, byte for byte
+        let (request_line, mut rest) = split_line(&bytes);
+        let mut fields: Vec<Vec<u8>> = Vec::new();
+        while !rest.is_empty() {
+            let (line, next) = split_line(rest);
+            rest = next;
+            if line.is_empty() {
+                break;
+            }
+            match fields.last_mut() {
+                // Obsolete line folding, the continuation is joined to the previous value
+                Some(field) if line.first().is_some_and(|b| OWS.contains(b)) => {
+                    field.truncate(trim_ows_end(field).len());
+                    field.push(b' ');
+                    field.extend_from_slice(trim_ows(line));
+                }
+                _ => fields.push(line.to_vec()),
+            }
+        }
+
+        let (method, target, httpver) = request_line
+            .split(|&b| b == b' ')
             .next_tuple()
             .ok_or(anyhow!("Invalid Request Line"))?;
 
         let mut marked = Vec::new();
+        let mut boundary = None;
 
         let mut req = RequestComponents::new();
         req.version = match httpver {
-            "HTTP/0.9" => Version::HTTP_09,
-            "HTTP/1" => Version::HTTP_10,
-            "HTTP/2" => Version::HTTP_2,
-            "HTTP/3" => Version::HTTP_3,
+            b"HTTP/0.9" => Version::HTTP_09,
+            b"HTTP/1" => Version::HTTP_10,
+            b"HTTP/2" => Version::HTTP_2,
+            b"HTTP/3" => Version::HTTP_3,
             _ => Version::HTTP_11,
         };
-        req.method = Method::try_from(method)?;
-
-        for header in lines.by_ref() {
-            let header = header?.trim().to_string();
-            if header.is_empty() {
-                break;
-            }
-            if pattern.is_match(&header) {
-                marked.push(Part::Header(header));
-                continue;
-            }
+        req.method = Method::from_bytes(method)?;
+        req.target = target.to_vec();
+        if bytes_pattern.is_match(target) {
+            marked.push(Part::Target);
+        }
 
-            let (key, value) = header
-                .split(':')
-                .map(str::trim)
-                .next_tuple()
+        for header in fields {
+            let colon = header
+                .iter()
+                .position(|&b| b == b':')
                 .ok_or(anyhow!("Invalid Header"))?;
+            let (key, value) = (&header[..colon], trim_ows(&header[colon + 1..]));
 
-            if key == "Host" {
-                let uri = Uri::builder()
-                    .scheme("http")
-                    .authority(value)
-                    .path_and_query(uri)
-                    .build()?;
-                req.uri = uri;
+            if bytes_pattern.is_match(&header) {
+                marked.push(Part::Header(req.head.len()));
+                req.head.push(Header {
+                    name: key.to_vec(),
+                    value: value.to_vec(),
+                });
+                continue;
             }
 
-            if key == "Content-Length" {
+            if key.eq_ignore_ascii_case(b"Content-Length") {
                 continue;
             }
-            req.insert_header(key.to_owned(), value.to_owned())?;
+            if key.eq_ignore_ascii_case(b"Content-Type") {
+                boundary = std::str::from_utf8(value).ok().and_then(Multipart::boundary);
+            }
+            req.insert_header(key, value)?;
         }
 
-        let body = lines.filter_map(|l| l.ok()).join("");
-        if pattern.is_match(&body) {
-            marked.push(Part::Body(body));
+        req.body = rest.to_vec();
+        if bytes_pattern.is_match(&req.body) {
+            marked.push(Part::Body);
         }
+        let multipart = boundary.and_then(|boundary| Multipart::parse(&req.body, &boundary));
 
         Ok(Self {
-            req: req,
+            req,
             marked,
-            pattern: pattern,
+            pattern,
+            bytes_pattern,
+            multipart,
+            target: None,
+            tls: false,
             attack_type: req_templ.attack_type
         })
     }
@@ -173,49 +280,167 @@ impl ReqTemplateFile {
     }
 }
 
+/// Percent-encodes the bytes that a [Uri] rejects: controls, spaces, non-ASCII bytes and the
+/// characters RFC 3986 leaves out. Anything else, including `%`, is kept as written.
+fn encode_target(target: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(target.len());
+    for &b in target {
+        if b <= b' ' || b >= 0x7f || b"\"<>\\^`{|}".contains(&b) {
+            encoded.extend_from_slice(format!("%{:02X}", b).as_bytes());
+        } else {
+            encoded.push(b);
+        }
+    }
+    encoded
+}
+
 impl RequestTemplate {
-    fn battering_ram(&self, pw: &str, mut req: Builder) -> Result<Vec<Request<Body>>> {
-        let mut body: &String = &self.req.body;
-        for part in &self.marked {
-            match part {
-                Part::Header(header) => {
-                    let (key, value) = header
-                        .split(':')
-                        .map(str::trim)
-                        .map(|s| self.pattern.replace_all(s, pw))
-                        .next_tuple()
-                        .ok_or(anyhow!("Invalid Header"))?;
-
-                    req = req.header(key.to_string(), value.to_string());
-                }
-                Part::Body(bd) => {
-                    body = bd;
-                }
-            }
+    /// HTTP version from the request line of the template.
+    pub fn version(&self) -> Version {
+        self.req.version
+    }
+
+    /// Send the requests over TLS (https) instead of plain HTTP.
+    pub fn use_tls(&mut self) {
+        self.tls = true;
+    }
+
+    /// Connect to `target` (e.g. `https://host:port`) instead of the host from the template, the
+    /// Host header and the request line are left as they are.
+    pub fn set_target(&mut self, target: Uri) -> Result<()> {
+        check_target(&target)?;
+        self.target = Some(target);
+        Ok(())
+    }
+
+    /// Uri of a request, the connection target is taken from the override set with
+    /// [set_target](Self::set_target), an absolute-form request-target or the Host header, in
+    /// that order. Bytes of the request-target that can't appear in a [Uri] are percent-encoded.
+    fn uri(&self, target: &[u8], headers: &[Header]) -> Result<Uri> {
+        let mut parts = if self.req.method == Method::CONNECT {
+            // Authority-form, the target is the host and port to tunnel to
+            let mut parts = Parts::default();
+            parts.authority = Some(Authority::try_from(target)?);
+            parts
+        } else {
+            Uri::try_from(encode_target(target))?.into_parts()
+        };
+        if let Some(connect) = &self.target {
+            parts.scheme = connect.scheme().cloned();
+            parts.authority = connect.authority().cloned();
+        }
+        if parts.authority.is_none() {
+            let host = headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(b"Host"))
+                .ok_or(anyhow!("No host to connect to, the template has no Host header"))?;
+            parts.authority = Some(Authority::try_from(&host.value[..])?);
+        }
+        if self.tls {
+            parts.scheme = Some(Scheme::HTTPS);
+        } else if parts.scheme.is_none() {
+            parts.scheme = Some(Scheme::HTTP);
         }
-        Ok(vec![req.body(Body::from(self.pattern.replace_all(body, pw).to_string()))?])
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some(PathAndQuery::from_static("/"));
+        }
+        Ok(Uri::from_parts(parts)?)
     }
 
-    fn cluster_bomb(&self, pw: &str, mut req: Builder) -> Result<Vec<Request<Body>>> {
+    fn battering_ram(&self, pw: &[u8], req: Builder) -> Result<Vec<Request<Body>>> {
+        let (body, boundary) = if !self.marked.iter().any(|part| matches!(part, Part::Body)) {
+            (self.req.body.clone(), None)
+        } else if let Some(multipart) = &self.multipart {
+            multipart.replace(&self.bytes_pattern, pw)
+        } else {
+            let body = self
+                .bytes_pattern
+                .replace_all(&self.req.body, NoExpand(pw));
+            (body.into_owned(), None)
+        };
+        let req = self.head(req, Some(pw), boundary.as_deref(), None)?;
+        Ok(vec![req.body(Body::from(body))?])
+    }
+
+    fn cluster_bomb(&self, _pw: &[u8], _req: Builder) -> Result<Vec<Request<Body>>> {
         Err(anyhow!("Not Implemented"))
     }
 
-    fn pitchfork(&self, pw: &str, mut req: Builder) -> Result<Vec<Request<Body>>> {
+    fn pitchfork(&self, _pw: &[u8], _req: Builder) -> Result<Vec<Request<Body>>> {
         Err(anyhow!("Not Implemented"))
     }
 
-    fn sniper(&self, pw: &str, mut req: Builder) -> Result<Vec<Request<Body>>> {
+    fn sniper(&self, _pw: &[u8], _req: Builder) -> Result<Vec<Request<Body>>> {
         Err(anyhow!("Not Implemented"))
     }
 
-    /// Replace the marked Parts with pw and build a new Request from them.
-    pub fn replace_then_request(&self, pw: &str) -> Result<Vec<Request<Body>>> {
-        let mut req = Request::builder()
+    /// Builder with the known components of the request already set.
+    fn builder(&self) -> Builder {
+        Request::builder()
             .version(self.req.version)
             .method(self.req.method.clone())
-            .uri(self.req.uri.clone());
-        let headers = req.headers_mut().ok_or(anyhow!("Builder has error"))?;
-        headers.clone_from(&self.req.head);
+    }
+
+    /// Add the uri and the headers in template order, replacing the pattern with pw in the marked
+    /// Parts, and record them as written in the [RequestTarget] and [OriginalHeaders]
+    /// extensions. `boundary` replaces the multipart boundary in the `Content-Type` header, and
+    /// `target` the request-target of the template.
+    fn head(
+        &self,
+        mut req: Builder,
+        pw: Option<&[u8]>,
+        boundary: Option<&str>,
+        target: Option<&[u8]>,
+    ) -> Result<Builder> {
+        let mut headers = self.req.head.clone();
+        let mut target = target.unwrap_or(&self.req.target).to_vec();
+        if let Some(pw) = pw {
+            let replace =
+                |bytes: &[u8]| self.bytes_pattern.replace_all(bytes, NoExpand(pw)).into_owned();
+            for part in &self.marked {
+                match *part {
+                    Part::Header(i) => {
+                        let header = &mut headers[i];
+                        header.name = replace(&header.name);
+                        header.value = replace(&header.value);
+                    }
+                    Part::Target => target = replace(&target),
+                    Part::Body => {}
+                }
+            }
+        }
+        if let (Some(multipart), Some(boundary)) = (&self.multipart, boundary) {
+            for header in &mut headers {
+                if header.name.eq_ignore_ascii_case(b"Content-Type") {
+                    header.value = multipart.content_type(&header.value, boundary);
+                }
+            }
+        }
+        for header in &headers {
+            req = req.header(&header.name[..], &header.value[..]);
+        }
+        Ok(req
+            .uri(self.uri(&target, &headers)?)
+            .extension(RequestTarget(target))
+            .extension(OriginalHeaders(headers)))
+    }
+
+    /// Build a new Request exactly as the template is, leaving the marked Parts unchanged.
+    pub fn request(&self) -> Result<Vec<Request<Body>>> {
+        let req = self.head(self.builder(), None, None, None)?;
+        Ok(vec![req.body(Body::from(self.req.body.clone()))?])
+    }
+
+    /// Build a new Request from the template with another request-target, such as a path being
+    /// discovered. The marked Parts are left unchanged.
+    pub fn request_to(&self, target: &[u8]) -> Result<Vec<Request<Body>>> {
+        let req = self.head(self.builder(), None, None, Some(target))?;
+        Ok(vec![req.body(Body::from(self.req.body.clone()))?])
+    }
+
+    /// Replace the marked Parts with pw and build a new Request from them.
+    pub fn replace_then_request(&self, pw: &[u8]) -> Result<Vec<Request<Body>>> {
+        let req = self.builder();
         match self.attack_type {
             AttackType::BatteringRam => self.battering_ram(pw, req),
             AttackType::ClusterBomb => self.cluster_bomb(pw, req),
@@ -224,3 +449,407 @@ impl RequestTemplate {
         }
     }
 }
+
+/// Template that is sent byte for byte as it is written, for the raw socket mode
+///
+/// Nothing is normalized: the casing, order and duplicates of the headers, malformed lines and
+/// every line ending are all kept. The connection target is taken from the `Host` header.
+pub struct RawTemplate {
+    bytes: Vec<u8>,
+    pattern: BytesRegex,
+    uri: Uri,
+    fix_length: bool,
+}
+
+impl RawTemplate {
+    /// Create a new [RawTemplate], if `fix_length` is set the `Content-Length` header is
+    /// recomputed after the payload is placed.
+    ///
+    /// The connection target is `target` if given, otherwise the host of an absolute-form
+    /// request-target or the Host header.
+    pub fn new(
+        mut file: File,
+        pattern: &str,
+        target: Option<Uri>,
+        tls: bool,
+        fix_length: bool,
+    ) -> Result<Self> {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let mut parts = match target {
+            Some(target) => {
+                check_target(&target)?;
+                target.into_parts()
+            }
+            None => match Self::absolute_target(&bytes) {
+                Some(target) => target.into_parts(),
+                None => {
+                    let host = Self::host(&bytes).ok_or(anyhow!("Template has no Host header"))?;
+                    let mut parts = Parts::default();
+                    parts.authority = Some(Authority::try_from(host)?);
+                    parts
+                }
+            },
+        };
+        if tls || parts.scheme.is_none() {
+            parts.scheme = Some(if tls { Scheme::HTTPS } else { Scheme::HTTP });
+        }
+        parts.path_and_query = Some(PathAndQuery::from_static("/"));
+        let uri = Uri::from_parts(parts)?;
+
+        Ok(Self {
+            bytes,
+            pattern: BytesRegex::new(pattern)?,
+            uri,
+            fix_length,
+        })
+    }
+
+    /// Uri of the connection target.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Position of the blank line ending the head, and the length of the line endings around it.
+    fn head_end(bytes: &[u8]) -> Option<(usize, usize)> {
+        let crlf = bytes.windows(4).position(|w| w == b"\r\n\r\n");
+        let lf = bytes.windows(2).position(|w| w == b"\n\n");
+        match (crlf, lf) {
+            (Some(crlf), Some(lf)) if lf < crlf => Some((lf, 2)),
+            (Some(crlf), _) => Some((crlf, 4)),
+            (None, lf) => lf.map(|lf| (lf, 2)),
+        }
+    }
+
+    /// Lines of the head (without the request line), each without its line ending.
+    fn header_lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+        let end = Self::head_end(bytes).map_or(bytes.len(), |(end, _)| end);
+        bytes[..end]
+            .split(|&b| b == b'\n')
+            .skip(1)
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    fn header_name<'a>(line: &'a [u8], name: &str) -> Option<&'a [u8]> {
+        let colon = line.iter().position(|&b| b == b':')?;
+        let (key, value) = line.split_at(colon);
+        key.trim_ascii()
+            .eq_ignore_ascii_case(name.as_bytes())
+            .then(|| value[1..].trim_ascii())
+    }
+
+    /// Scheme and authority of the request-target of the request line, if it is in
+    /// absolute-form. The path and query may hold markers, so they are left out.
+    fn absolute_target(bytes: &[u8]) -> Option<Uri> {
+        let (line, _) = split_line(bytes);
+        let target = line.split(|&b| b == b' ').nth(1)?;
+        let scheme_end = target.windows(3).position(|w| w == b"://")?;
+        let authority_end = target[scheme_end + 3..]
+            .iter()
+            .position(|&b| matches!(b, b'/' | b'?' | b'#'))
+            .map_or(target.len(), |end| scheme_end + 3 + end);
+        let uri = Uri::try_from(&target[..authority_end]).ok()?;
+        check_target(&uri).ok().map(|_| uri)
+    }
+
+    fn host(bytes: &[u8]) -> Option<String> {
+        Self::header_lines(bytes)
+            .find_map(|line| Self::header_name(line, "host"))
+            .and_then(|host| String::from_utf8(host.to_vec()).ok())
+    }
+
+    /// Sets the `Content-Length` header to the length of the body, adding it if the body isn't
+    /// empty. Every other byte is left as it was.
+    fn fix_length(req: Vec<u8>) -> Vec<u8> {
+        let (end, sep) = match Self::head_end(&req) {
+            Some(head_end) => head_end,
+            None => return req,
+        };
+        let body_len = req.len() - end - sep;
+        let eol: &[u8] = if sep == 4 { b"\r\n" } else { b"\n" };
+
+        let mut found = false;
+        let mut head: Vec<u8> = req[..end]
+            .split(|&b| b == b'\n')
+            .enumerate()
+            .map(|(i, line)| {
+                let stripped = line.strip_suffix(b"\r").unwrap_or(line);
+                if i == 0 || Self::header_name(stripped, "content-length").is_none() {
+                    return line.to_vec();
+                }
+                found = true;
+                let colon = line.iter().position(|&b| b == b':').unwrap();
+                let mut fixed = line[..=colon].to_vec();
+                fixed.extend_from_slice(format!(" {}", body_len).as_bytes());
+                fixed.extend_from_slice(&line[stripped.len()..]);
+                fixed
+            })
+            .collect::<Vec<_>>()
+            .join(&b'\n');
+        if !found && body_len > 0 {
+            head.extend_from_slice(eol);
+            head.extend_from_slice(format!("Content-Length: {}", body_len).as_bytes());
+        }
+        head.extend_from_slice(&req[end..]);
+        head
+    }
+
+    /// The template exactly as it is written.
+    pub fn request(&self) -> Vec<u8> {
+        if self.fix_length {
+            Self::fix_length(self.bytes.clone())
+        } else {
+            self.bytes.clone()
+        }
+    }
+
+    /// Replace every match of the pattern with pw.
+    pub fn replace(&self, pw: &[u8]) -> Vec<u8> {
+        let req = self.pattern.replace_all(&self.bytes, NoExpand(pw)).into_owned();
+        if self.fix_length {
+            Self::fix_length(req)
+        } else {
+            req
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::{HeaderMap, HOST};
+
+    fn fix_length(req: &[u8]) -> Vec<u8> {
+        RawTemplate::fix_length(req.to_vec())
+    }
+
+    #[test]
+    fn fix_length_adds_the_header_to_crlf_templates() {
+        assert_eq!(
+            fix_length(b"POST /login HTTP/1.1\r\nHost: a\r\n\r\npin=0042"),
+            b"POST /login HTTP/1.1\r\nHost: a\r\nContent-Length: 8\r\n\r\npin=0042"
+        );
+    }
+
+    #[test]
+    fn fix_length_adds_the_header_to_lf_templates() {
+        assert_eq!(
+            fix_length(b"POST /login HTTP/1.1\nHost: a\n\npin=0042\n"),
+            b"POST /login HTTP/1.1\nHost: a\nContent-Length: 9\n\npin=0042\n"
+        );
+    }
+
+    #[test]
+    fn fix_length_follows_the_blank_line_of_mixed_templates() {
+        // The head ends at the first blank line, whichever line endings it uses
+        assert_eq!(
+            fix_length(b"POST / HTTP/1.1\r\nHost: a\n\nab\r\n\r\ncd"),
+            b"POST / HTTP/1.1\r\nHost: a\nContent-Length: 8\n\nab\r\n\r\ncd"
+        );
+        assert_eq!(
+            fix_length(b"POST / HTTP/1.1\nHost: a\r\n\r\nab\n\ncd"),
+            b"POST / HTTP/1.1\nHost: a\r\nContent-Length: 6\r\n\r\nab\n\ncd"
+        );
+    }
+
+    #[test]
+    fn fix_length_rewrites_an_existing_header_in_place() {
+        assert_eq!(
+            fix_length(b"POST / HTTP/1.1\r\ncontent-LENGTH :  99\r\nHost: a\r\n\r\nabcd"),
+            b"POST / HTTP/1.1\r\ncontent-LENGTH : 4\r\nHost: a\r\n\r\nabcd"
+        );
+        assert_eq!(
+            fix_length(b"GET / HTTP/1.1\nContent-Length: 12\nHost: a\n\n"),
+            b"GET / HTTP/1.1\nContent-Length: 0\nHost: a\n\n"
+        );
+    }
+
+    #[test]
+    fn fix_length_leaves_templates_without_a_body() {
+        let req = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(fix_length(req), req);
+        // Without a blank line there is no body to measure
+        let req = b"GET / HTTP/1.1\r\nHost: a\r\n";
+        assert_eq!(fix_length(req), req);
+    }
+
+    /// Parses a template written to a temporary file
+    fn template(name: &str, bytes: &[u8]) -> RequestTemplate {
+        let path = std::env::temp_dir().join(format!("rip-intruder-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let file = ReqTemplateFile::new(File::open(&path).unwrap(), "§§", AttackType::BatteringRam);
+        let templ = RequestTemplate::try_from(file.unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        templ
+    }
+
+    #[tokio::test]
+    async fn multipart_boundary_collisions_rewrite_the_content_type() {
+        let templ = template(
+            "multipart",
+            b"POST /upload HTTP/1.1\r\nHost: a\r\n\
+              Content-Type: multipart/form-data; boundary=XyZ\r\n\r\n\
+              --XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"x.txt\"\r\n\r\n\
+              \xc2\xa7\xc2\xa7\r\n--XyZ--\r\n",
+        );
+
+        let req = templ.replace_then_request(b"ok").unwrap().remove(0);
+        assert_eq!(req.headers()["content-type"], "multipart/form-data; boundary=XyZ");
+
+        let req = templ.replace_then_request(b"\r\n--XyZ--\r\n").unwrap().remove(0);
+        assert_eq!(req.headers()["content-type"], "multipart/form-data; boundary=XyZ0");
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(
+            &body[..],
+            b"--XyZ0\r\nContent-Disposition: form-data; name=\"file\"; filename=\"x.txt\"\r\n\r\n\
+              \r\n--XyZ--\r\n\r\n--XyZ0--\r\n"
+        );
+    }
+
+    #[test]
+    fn templates_are_parsed_as_bytes() {
+        let templ = template(
+            "bytes",
+            b"GET /caf\xe9 HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9 \r\nX-Folded: a \r\n\t b\r\n\
+              X-Marked: \xff\xc2\xa7\xc2\xa7\r\n\r\n",
+        );
+        assert_eq!(templ.req.target, b"/caf\xe9");
+        let values: Vec<_> = templ.req.head.iter().map(|h| (&h.name[..], &h.value[..])).collect();
+        assert_eq!(
+            values,
+            [
+                (&b"Host"[..], &b"a"[..]),
+                (b"X-Name", b"caf\xe9"),
+                (b"X-Folded", b"a b"),
+                (b"X-Marked", b"\xff\xc2\xa7\xc2\xa7"),
+            ]
+        );
+        assert!(matches!(templ.marked[..], [Part::Header(3)]));
+    }
+
+    #[test]
+    fn invalid_target_bytes_are_percent_encoded() {
+        let templ = template(
+            "target",
+            b"GET /caf\xe9/\"x\"?q=\xc2\xa7\xc2\xa7&a=%41 HTTP/1.1\r\nHost: a\r\n\r\n",
+        );
+        let req = templ.request().unwrap().remove(0);
+        assert_eq!(req.uri(), "http://a/caf%E9/%22x%22?q=%C2%A7%C2%A7&a=%41");
+        let req = templ.replace_then_request(b"a b\xff{|}").unwrap().remove(0);
+        assert_eq!(req.uri(), "http://a/caf%E9/%22x%22?q=a%20b%FF%7B%7C%7D&a=%41");
+        // The request-target is still written as it is in race and raw mode
+        let RequestTarget(target) = req.extensions().get().unwrap();
+        assert_eq!(target, b"/caf\xe9/\"x\"?q=a b\xff{|}&a=%41");
+
+        for b in 0..=u8::MAX {
+            let target = [b'/', b'?', b];
+            assert!(Uri::try_from(encode_target(&target)).is_ok(), "{:#x}", b);
+        }
+    }
+
+    #[test]
+    fn non_utf8_headers_reach_the_request() {
+        let templ = template(
+            "bytes-request",
+            b"GET / HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9\r\nX-Marked: \xc2\xa7\xc2\xa7\r\n\r\n",
+        );
+        let req = templ.replace_then_request(b"\xff\xfe").unwrap().remove(0);
+        assert_eq!(req.headers()["x-name"].as_bytes(), b"caf\xe9");
+        assert_eq!(req.headers()["x-marked"].as_bytes(), b"\xff\xfe");
+    }
+
+    /// The uri and headers of the request built from the template
+    fn sent(templ: &RequestTemplate, pw: Option<&[u8]>) -> (String, HeaderMap) {
+        let req = match pw {
+            Some(pw) => templ.replace_then_request(pw),
+            None => templ.request(),
+        };
+        let req = req.unwrap().remove(0);
+        (req.uri().to_string(), req.headers().clone())
+    }
+
+    #[test]
+    fn uris_come_from_the_host_header() {
+        let mut templ = template(
+            "uri-host",
+            b"GET /a?b=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n",
+        );
+        assert_eq!(sent(&templ, None).0, "http://example.com:8080/a?b=1");
+        templ.use_tls();
+        assert_eq!(sent(&templ, None).0, "https://example.com:8080/a?b=1");
+
+        let templ = template(
+            "uri-marked",
+            b"GET / HTTP/1.1\r\nHost: \xc2\xa7\xc2\xa7.example.com\r\n\r\n",
+        );
+        assert_eq!(sent(&templ, Some(b"admin")).0, "http://admin.example.com/");
+    }
+
+    #[test]
+    fn absolute_form_targets_need_no_host_header() {
+        let templ = template("uri-absolute", b"GET https://example.com/a?b=1 HTTP/1.1\r\n\r\n");
+        let (uri, headers) = sent(&templ, None);
+        assert_eq!(uri, "https://example.com/a?b=1");
+        assert!(headers.get(HOST).is_none());
+
+        // The request-target wins over the Host header
+        let templ = template(
+            "uri-absolute-host",
+            b"GET http://127.0.0.1:8081/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        let (uri, headers) = sent(&templ, None);
+        assert_eq!(uri, "http://127.0.0.1:8081/");
+        assert_eq!(headers[HOST], "example.com");
+    }
+
+    #[test]
+    fn connect_targets_are_authorities() {
+        let templ = template(
+            "uri-connect",
+            b"CONNECT internal.example.com:443 HTTP/1.1\r\nHost: internal.example.com:443\r\n\r\n",
+        );
+        let req = templ.request().unwrap().remove(0);
+        assert_eq!(req.method(), Method::CONNECT);
+        assert_eq!(req.uri().authority().unwrap(), "internal.example.com:443");
+        let RequestTarget(target) = req.extensions().get().unwrap();
+        assert_eq!(target, b"internal.example.com:443");
+    }
+
+    #[test]
+    fn target_overrides_keep_the_host_header() {
+        let mut templ = template(
+            "uri-override",
+            b"GET http://example.com/a HTTP/1.1\r\nHost: www.example.com\r\n\r\n",
+        );
+        templ.set_target("https://10.0.0.1:8443".parse().unwrap()).unwrap();
+        let (uri, headers) = sent(&templ, None);
+        assert_eq!(uri, "https://10.0.0.1:8443/a");
+        assert_eq!(headers[HOST], "www.example.com");
+
+        assert!(templ.set_target("/no/authority".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn templates_without_a_host_are_errors() {
+        let templ = template("uri-no-host", b"GET /a HTTP/1.1\r\nX-Host: example.com\r\n\r\n");
+        let err = templ.request().unwrap_err().to_string();
+        assert_eq!(err, "No host to connect to, the template has no Host header");
+    }
+
+    #[test]
+    fn raw_targets_come_from_the_scheme_and_authority() {
+        let target = |line: &[u8]| RawTemplate::absolute_target(line).map(|uri| uri.to_string());
+        assert_eq!(
+            target(b"GET http://127.0.0.1:8083/\xc2\xa7\xc2\xa7 HTTP/1.1\r\n").as_deref(),
+            Some("http://127.0.0.1:8083/")
+        );
+        assert_eq!(
+            target(b"GET https://example.com?q=\xc2\xa7\xc2\xa7 HTTP/1.1\n").as_deref(),
+            Some("https://example.com/")
+        );
+        assert_eq!(target(b"GET /path HTTP/1.1\r\n"), None);
+        assert_eq!(target(b"GET ftp://example.com/ HTTP/1.1\r\n"), None);
+        assert_eq!(target(b"CONNECT example.com:443 HTTP/1.1\r\n"), None);
+    }
+}
@@ -0,0 +1,372 @@
+//! Rules
+//!
+//! This module houses a subset of the hashcat rule engine, used for mangling the payloads on the
+//! fly so that a wordlist can be expanded without writing the mutations to disk.
+use anyhow::{anyhow, Context, Result};
+
+use itertools::Itertools;
+
+use std::fs::File;
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+use std::str::Chars;
+use std::sync::Arc;
+
+/// A single rule function, positions are already decoded from hashcat's `0-9A-Z` notation.
+#[derive(Clone, Debug)]
+enum Op {
+    Noop,
+    Lower,
+    Upper,
+    Capitalize,
+    InvertCapitalize,
+    ToggleAll,
+    Toggle(usize),
+    Reverse,
+    Duplicate,
+    DuplicateN(usize),
+    Reflect,
+    RotateLeft,
+    RotateRight,
+    Append(char),
+    Prepend(char),
+    DeleteFirst,
+    DeleteLast,
+    Delete(usize),
+    Truncate(usize),
+    Extract(usize, usize),
+    Insert(usize, char),
+    Overwrite(usize, char),
+    Replace(char, char),
+    Purge(char),
+    DuplicateFirst(usize),
+    DuplicateLast(usize),
+    DuplicateAll,
+}
+
+impl Op {
+    /// Parses the next function out of a rule, skipping any whitespace before it.
+    fn parse(chars: &mut Chars) -> Result<Option<Self>> {
+        let name = match chars.find(|c| !c.is_whitespace()) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let mut arg = || chars.next().ok_or(anyhow!("Missing argument for '{}'", name));
+        let op = match name {
+            ':' => Self::Noop,
+            'l' => Self::Lower,
+            'u' => Self::Upper,
+            'c' => Self::Capitalize,
+            'C' => Self::InvertCapitalize,
+            't' => Self::ToggleAll,
+            'T' => Self::Toggle(Self::position(arg()?)?),
+            'r' => Self::Reverse,
+            'd' => Self::Duplicate,
+            'p' => Self::DuplicateN(Self::position(arg()?)?),
+            'f' => Self::Reflect,
+            '{' => Self::RotateLeft,
+            '}' => Self::RotateRight,
+            '$' => Self::Append(arg()?),
+            '^' => Self::Prepend(arg()?),
+            '[' => Self::DeleteFirst,
+            ']' => Self::DeleteLast,
+            'D' => Self::Delete(Self::position(arg()?)?),
+            '\'' => Self::Truncate(Self::position(arg()?)?),
+            'x' => Self::Extract(Self::position(arg()?)?, Self::position(arg()?)?),
+            'i' => Self::Insert(Self::position(arg()?)?, arg()?),
+            'o' => Self::Overwrite(Self::position(arg()?)?, arg()?),
+            's' => Self::Replace(arg()?, arg()?),
+            '@' => Self::Purge(arg()?),
+            'z' => Self::DuplicateFirst(Self::position(arg()?)?),
+            'Z' => Self::DuplicateLast(Self::position(arg()?)?),
+            'q' => Self::DuplicateAll,
+            _ => return Err(anyhow!("Unsupported rule function '{}'", name)),
+        };
+        Ok(Some(op))
+    }
+
+    /// Decodes a position, `0-9` followed by `A-Z` for 10 to 35.
+    fn position(c: char) -> Result<usize> {
+        match c {
+            '0'..='9' => Ok(c as usize - '0' as usize),
+            'A'..='Z' => Ok(c as usize - 'A' as usize + 10),
+            _ => Err(anyhow!("Invalid position '{}'", c)),
+        }
+    }
+
+    fn apply(&self, word: &mut Vec<char>) {
+        match *self {
+            Self::Noop => {}
+            Self::Lower => word.iter_mut().for_each(|c| *c = c.to_ascii_lowercase()),
+            Self::Upper => word.iter_mut().for_each(|c| *c = c.to_ascii_uppercase()),
+            Self::Capitalize => {
+                Self::Lower.apply(word);
+                if let Some(c) = word.first_mut() {
+                    *c = c.to_ascii_uppercase();
+                }
+            }
+            Self::InvertCapitalize => {
+                Self::Upper.apply(word);
+                if let Some(c) = word.first_mut() {
+                    *c = c.to_ascii_lowercase();
+                }
+            }
+            Self::ToggleAll => word.iter_mut().for_each(Self::toggle),
+            Self::Toggle(n) => {
+                if let Some(c) = word.get_mut(n) {
+                    Self::toggle(c)
+                }
+            }
+            Self::Reverse => word.reverse(),
+            Self::Duplicate => word.extend_from_within(..),
+            Self::DuplicateN(n) => *word = word.repeat(n + 1),
+            Self::Reflect => {
+                let reversed: Vec<char> = word.iter().rev().copied().collect();
+                word.extend(reversed);
+            }
+            Self::RotateLeft => {
+                if !word.is_empty() {
+                    word.rotate_left(1)
+                }
+            }
+            Self::RotateRight => {
+                if !word.is_empty() {
+                    word.rotate_right(1)
+                }
+            }
+            Self::Append(c) => word.push(c),
+            Self::Prepend(c) => word.insert(0, c),
+            Self::DeleteFirst => {
+                if !word.is_empty() {
+                    word.remove(0);
+                }
+            }
+            Self::DeleteLast => {
+                word.pop();
+            }
+            Self::Delete(n) => {
+                if n < word.len() {
+                    word.remove(n);
+                }
+            }
+            Self::Truncate(n) => word.truncate(n),
+            Self::Extract(n, m) => {
+                // Like hashcat, a range running past the end leaves the word as it is
+                if n + m <= word.len() {
+                    *word = word[n..n + m].to_vec();
+                }
+            }
+            Self::Insert(n, c) => {
+                if n <= word.len() {
+                    word.insert(n, c)
+                }
+            }
+            Self::Overwrite(n, c) => {
+                if let Some(old) = word.get_mut(n) {
+                    *old = c
+                }
+            }
+            Self::Replace(from, to) => word
+                .iter_mut()
+                .filter(|c| **c == from)
+                .for_each(|c| *c = to),
+            Self::Purge(c) => word.retain(|&old| old != c),
+            Self::DuplicateFirst(n) => {
+                if let Some(&c) = word.first() {
+                    word.splice(0..0, std::iter::repeat_n(c, n));
+                }
+            }
+            Self::DuplicateLast(n) => {
+                if let Some(&c) = word.last() {
+                    word.extend(std::iter::repeat_n(c, n));
+                }
+            }
+            Self::DuplicateAll => *word = word.iter().flat_map(|&c| [c, c]).collect(),
+        }
+    }
+
+    fn toggle(c: &mut char) {
+        *c = if c.is_ascii_lowercase() {
+            c.to_ascii_uppercase()
+        } else {
+            c.to_ascii_lowercase()
+        }
+    }
+}
+
+/// A rule, i.e. one line of a rule file, made up of functions applied from left to right.
+#[derive(Clone, Debug)]
+struct Rule(Vec<Op>);
+
+impl Rule {
+    /// Applies the rule to a word, words that aren't valid UTF-8 are handled as Latin-1 so that
+    /// every byte is one character.
+    fn apply(&self, word: &[u8]) -> Vec<u8> {
+        let utf8 = std::str::from_utf8(word).ok();
+        let mut word: Vec<char> = match utf8 {
+            Some(word) => word.chars().collect(),
+            None => word.iter().map(|&b| b as char).collect(),
+        };
+        for op in &self.0 {
+            op.apply(&mut word);
+        }
+        match utf8 {
+            Some(_) => word.into_iter().collect::<String>().into_bytes(),
+            None => word
+                .into_iter()
+                .flat_map(|c| match u8::try_from(c) {
+                    Ok(b) => vec![b],
+                    Err(_) => c.to_string().into_bytes(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Set of rules loaded from a hashcat-compatible `.rule` file
+///
+/// Every payload is mangled by each rule in turn, so the number of payloads is multiplied by the
+/// number of rules.
+#[derive(Clone, Debug)]
+pub struct Rules {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl Rules {
+    /// Load the rules from a file, empty lines and lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut chars = line.chars();
+            let mut ops = Vec::new();
+            while let Some(op) =
+                Op::parse(&mut chars).with_context(|| format!("Invalid rule on line {}", i + 1))?
+            {
+                ops.push(op);
+            }
+            rules.push(Rule(ops));
+        }
+        if rules.is_empty() {
+            return Err(anyhow!("No rules in {}", path.display()));
+        }
+        Ok(Self {
+            rules: Arc::new(rules),
+        })
+    }
+
+    /// Number of rules, i.e. how many payloads each word expands to.
+    pub fn count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Lazily apply every rule to every word.
+    pub fn apply<T>(&self, words: T) -> impl Iterator<Item = Result<Vec<u8>>>
+    where
+        T: Iterator<Item = Result<Vec<u8>>>,
+    {
+        let rules = self.rules.clone();
+        words
+            .map_ok(move |word| {
+                let rules = rules.clone();
+                (0..rules.len()).map(move |i| rules[i].apply(&word))
+            })
+            .flatten_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(line: &str) -> Result<Rule> {
+        let mut chars = line.chars();
+        let mut ops = Vec::new();
+        while let Some(op) = Op::parse(&mut chars)? {
+            ops.push(op);
+        }
+        Ok(Rule(ops))
+    }
+
+    fn apply(line: &str, word: &str) -> String {
+        String::from_utf8(rule(line).unwrap().apply(word.as_bytes())).unwrap()
+    }
+
+    /// The examples of hashcat's rule-based attack documentation
+    #[test]
+    fn functions_match_hashcat() {
+        let cases = [
+            (":", "p@ssW0rd"),
+            ("l", "p@ssw0rd"),
+            ("u", "P@SSW0RD"),
+            ("c", "P@ssw0rd"),
+            ("C", "p@SSW0RD"),
+            ("t", "P@SSw0RD"),
+            ("T3", "p@sSW0rd"),
+            ("r", "dr0Wss@p"),
+            ("d", "p@ssW0rdp@ssW0rd"),
+            ("p2", "p@ssW0rdp@ssW0rdp@ssW0rd"),
+            ("f", "p@ssW0rddr0Wss@p"),
+            ("{", "@ssW0rdp"),
+            ("}", "dp@ssW0r"),
+            ("$1", "p@ssW0rd1"),
+            ("^1", "1p@ssW0rd"),
+            ("[", "@ssW0rd"),
+            ("]", "p@ssW0r"),
+            ("D3", "p@sW0rd"),
+            ("x04", "p@ss"),
+            ("i4!", "p@ss!W0rd"),
+            ("o3$", "p@s$W0rd"),
+            ("'6", "p@ssW0"),
+            ("ss$", "p@$$W0rd"),
+            ("@s", "p@W0rd"),
+            ("z2", "ppp@ssW0rd"),
+            ("Z2", "p@ssW0rddd"),
+            ("q", "pp@@ssssWW00rrdd"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(apply(line, "p@ssW0rd"), expected, "rule {}", line);
+        }
+    }
+
+    #[test]
+    fn functions_are_applied_left_to_right() {
+        assert_eq!(apply("c $2 $0 $2 $4", "summer"), "Summer2024");
+        assert_eq!(apply("sa@ se3 so0", "password"), "p@ssw0rd");
+        assert_eq!(apply("r c", "abc"), "Cba");
+    }
+
+    #[test]
+    fn out_of_range_positions_leave_the_word() {
+        for line in ["T9", "D9", "x93", "i9!", "o9!"] {
+            assert_eq!(apply(line, "abc"), "abc", "rule {}", line);
+        }
+        assert_eq!(apply("'9", "abc"), "abc");
+        assert_eq!(apply("x15", "abc"), "abc");
+        assert_eq!(apply("x12", "abc"), "bc");
+        assert_eq!(apply("x03", "abc"), "abc");
+        assert_eq!(apply("{ } [ ] z2 Z2", ""), "");
+    }
+
+    #[test]
+    fn positions_above_nine_use_letters() {
+        assert_eq!(apply("'A", "0123456789abcdef"), "0123456789");
+        assert_eq!(apply("TB", "0123456789abcdef"), "0123456789aBcdef");
+    }
+
+    #[test]
+    fn non_utf8_words_are_mangled_per_byte() {
+        let rule = rule("u $\u{e9}").unwrap();
+        assert_eq!(rule.apply(b"caf\xe9"), b"CAF\xe9\xe9");
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for line in ["$", "T", "Ta", "x1", "s1", "X"] {
+            assert!(rule(line).is_err(), "rule {}", line);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! TLS
+//!
+//! This module houses the TLS configuration shared by every connection to the target.
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ServerName};
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Accepts any certificate, targets often use self-signed or otherwise invalid certificates.
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Client configuration that doesn't verify the server's certificate.
+pub fn client_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+        .with_no_client_auth()
+}
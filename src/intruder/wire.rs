@@ -0,0 +1,384 @@
+//! Wire
+//!
+//! This module houses the HTTP/1.1 wire format helpers used by the modes that need more control
+//! over the connection than hyper's client gives.
+use anyhow::{anyhow, Result};
+
+use hyper::body::{self, Bytes};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use hyper::{Body, Request, Response, Uri, Version};
+
+use rustls::ServerName;
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::encoding::Truncated;
+use crate::request_template::{OriginalHeaders, RequestTarget};
+use crate::tls;
+
+/// A connection to the target, either plain TCP or TLS
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Serializes a request into the bytes that are sent on the wire.
+///
+/// The request-target and headers are written as they are in the [RequestTarget] and
+/// [OriginalHeaders] extensions when the request has them, keeping the absolute-form of the
+/// request line and the casing, order and duplicates of the headers. The `Host` and `Content-Length` headers are
+/// added when the request doesn't have them.
+pub async fn serialize(req: Request<Body>) -> Result<Vec<u8>> {
+    let (parts, body) = req.into_parts();
+    let body = body::to_bytes(body).await?;
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+
+    let mut out = format!("{} ", parts.method).into_bytes();
+    match parts.extensions.get::<RequestTarget>() {
+        Some(RequestTarget(target)) => out.extend_from_slice(target),
+        None => out.extend_from_slice(path.as_bytes()),
+    }
+    out.extend_from_slice(b" HTTP/1.1\r\n");
+    if !parts.headers.contains_key(HOST) {
+        let host = parts.uri.authority().ok_or(anyhow!("Request has no host"))?;
+        out.extend_from_slice(format!("Host: {}\r\n", host).as_bytes());
+    }
+    match parts.extensions.get::<OriginalHeaders>() {
+        Some(OriginalHeaders(headers)) => {
+            for header in headers {
+                out.extend_from_slice(&header.name);
+                out.extend_from_slice(b": ");
+                out.extend_from_slice(&header.value);
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+        None => {
+            for (key, value) in &parts.headers {
+                out.extend_from_slice(key.as_str().as_bytes());
+                out.extend_from_slice(b": ");
+                out.extend_from_slice(value.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+    if !body.is_empty() && !parts.headers.contains_key(CONTENT_LENGTH) {
+        out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Opens a connection to the host of the uri, with Nagle's algorithm disabled. Uris with the
+/// `https` scheme are connected with TLS.
+pub async fn connect(uri: &Uri) -> Result<Box<dyn Connection>> {
+    let host = uri.host().ok_or(anyhow!("Request has no host"))?;
+    let tls = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(true)?;
+    if !tls {
+        return Ok(Box::new(stream));
+    }
+
+    let connector = TlsConnector::from(Arc::new(tls::client_config()));
+    let name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))?;
+    Ok(Box::new(connector.connect(name, stream).await?))
+}
+
+/// Reads a single response, the body is delimited by `Content-Length`, chunked encoding or the
+/// connection closing.
+///
+/// Responses to a `HEAD` request (`head`), interim, 204 and 304 responses have no body, whatever
+/// their headers say. At most `limit` bytes of the body are read if given, responses cut off
+/// there get a [Truncated] extension and their connection can't be used any further.
+///
+/// The parser is lenient, so that responses to malformed requests can still be read: extra
+/// spaces and obsolete line folding are accepted, headers that can't be parsed are skipped and
+/// interim (1xx) responses are read past.
+pub async fn read_response<S>(
+    stream: &mut S,
+    head: bool,
+    limit: Option<usize>,
+) -> Result<Response<Body>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut config = httparse::ParserConfig::default();
+    config
+        .allow_spaces_after_header_name_in_responses(true)
+        .allow_multiple_spaces_in_response_status_delimiters(true)
+        .allow_obsolete_multiline_headers_in_responses(true)
+        .ignore_invalid_headers_in_responses(true);
+
+    let mut buf = Vec::new();
+    let (mut builder, code, length, chunked) = loop {
+        let mut headers = [httparse::EMPTY_HEADER; 128];
+        let mut resp = httparse::Response::new(&mut headers);
+        let head_len = match config.parse_response(&mut resp, &buf)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => {
+                if stream.read_buf(&mut buf).await? == 0 {
+                    return Err(anyhow!("Connection closed before the response was received"));
+                }
+                continue;
+            }
+        };
+        let code = resp.code.unwrap_or_default();
+        if (100..200).contains(&code) && code != 101 {
+            buf.drain(..head_len);
+            continue;
+        }
+
+        let mut builder = Response::builder()
+            .status(code)
+            .version(match resp.version {
+                Some(0) => Version::HTTP_10,
+                _ => Version::HTTP_11,
+            });
+        let (mut length, mut chunked) = (None, false);
+        for header in resp.headers.iter() {
+            if header.name.eq_ignore_ascii_case(CONTENT_LENGTH.as_str()) {
+                length = std::str::from_utf8(header.value)?.trim().parse::<usize>().ok();
+            }
+            if header.name.eq_ignore_ascii_case(TRANSFER_ENCODING.as_str()) {
+                chunked = header.value.eq_ignore_ascii_case(b"chunked");
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(header.name.as_bytes()),
+                HeaderValue::from_bytes(header.value),
+            ) {
+                builder = builder.header(name, value);
+            }
+        }
+        buf.drain(..head_len);
+        break (builder, code, length, chunked);
+    };
+    let mut rest = buf;
+
+    // One more byte than the limit, to tell whether the body was cut off
+    let max = limit.map_or(usize::MAX, |limit| limit + 1);
+    let mut body = if head || code < 200 || code == 204 || code == 304 {
+        Vec::new()
+    } else if chunked {
+        read_chunked(stream, rest, max).await?
+    } else if let Some(length) = length {
+        while rest.len() < length.min(max) {
+            if stream.read_buf(&mut rest).await? == 0 {
+                return Err(anyhow!("Connection closed before the body was received"));
+            }
+        }
+        rest.truncate(length);
+        rest
+    } else {
+        while rest.len() < max {
+            if stream.read_buf(&mut rest).await? == 0 {
+                break;
+            }
+        }
+        rest
+    };
+    if let Some(limit) = limit.filter(|&limit| body.len() > limit) {
+        body.truncate(limit);
+        builder = builder.extension(Truncated);
+    }
+    Ok(builder.body(Body::from(Bytes::from(body)))?)
+}
+
+/// Decodes a chunked body, `buf` holds what was already read past the head. Stops once `max`
+/// bytes of it were decoded.
+async fn read_chunked<S>(stream: &mut S, mut buf: Vec<u8>, max: usize) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut body = Vec::new();
+    while body.len() < max {
+        let line_end = loop {
+            if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+                break pos;
+            }
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(anyhow!("Connection closed inside a chunked body"));
+            }
+        };
+        let size = std::str::from_utf8(&buf[..line_end])?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)?;
+        // The chunk, followed by its CRLF
+        while buf.len() < line_end + 2 + size + 2 {
+            if body.len() + buf.len() - (line_end + 2) >= max {
+                body.extend_from_slice(&buf[line_end + 2..]);
+                return Ok(body);
+            }
+            if stream.read_buf(&mut buf).await? == 0 {
+                if size == 0 {
+                    return Ok(body);
+                }
+                return Err(anyhow!("Connection closed inside a chunked body"));
+            }
+        }
+        if size == 0 {
+            return Ok(body);
+        }
+        body.extend_from_slice(&buf[line_end + 2..line_end + 2 + size]);
+        buf.drain(..line_end + 2 + size + 2);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use tokio::time::timeout;
+
+    /// A stream that hands out the bytes a few at a time, then closes if `close` is set and
+    /// stays open otherwise.
+    fn trickle(bytes: &[u8], close: bool) -> DuplexStream {
+        let (client, mut server) = duplex(3);
+        let bytes = bytes.to_vec();
+        tokio::spawn(async move {
+            server.write_all(&bytes).await.unwrap();
+            if !close {
+                std::future::pending::<()>().await;
+            }
+        });
+        client
+    }
+
+    /// Reads a response from a stream that closes once the bytes are sent
+    async fn read_closed(bytes: &[u8]) -> Result<Response<Body>> {
+        let mut stream = trickle(bytes, true);
+        timeout(Duration::from_secs(5), read_response(&mut stream, false, None)).await?
+    }
+
+    async fn read(bytes: &[u8], head: bool, limit: Option<usize>) -> Result<Response<Body>> {
+        let mut stream = trickle(bytes, false);
+        timeout(Duration::from_secs(5), read_response(&mut stream, head, limit)).await?
+    }
+
+    async fn body(resp: Response<Body>) -> Vec<u8> {
+        body::to_bytes(resp.into_body()).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_decoded() {
+        let resp = read(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4;ext=1\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nTrailer: x\r\n\r\n",
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(body(resp).await, b"Wikipedia in\r\n\r\nchunks.");
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_cut_off_at_the_limit() {
+        let resp = read(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n",
+            false,
+            Some(6),
+        )
+        .await
+        .unwrap();
+        assert!(resp.extensions().get::<Truncated>().is_some());
+        assert_eq!(body(resp).await, b"Wikipe");
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_must_be_complete() {
+        let resp = read_closed(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\nWiki");
+        assert!(resp.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bodies_are_delimited_by_content_length() {
+        let resp = read(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, next", false, None)
+            .await
+            .unwrap();
+        assert!(resp.extensions().get::<Truncated>().is_none());
+        assert_eq!(body(resp).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn bodies_without_length_end_with_the_connection() {
+        let resp = read_closed(b"HTTP/1.0 200 OK\r\n\r\nuntil the end").await.unwrap();
+        assert_eq!(resp.version(), Version::HTTP_10);
+        assert_eq!(body(resp).await, b"until the end");
+    }
+
+    #[tokio::test]
+    async fn bodyless_responses_are_not_waited_for() {
+        for status in ["204 No Content", "304 Not Modified"] {
+            let resp = read(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes(), false, None)
+                .await
+                .unwrap();
+            assert_eq!(body(resp).await, b"");
+        }
+        let resp = read(b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n", true, None)
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "1234");
+        assert_eq!(body(resp).await, b"");
+    }
+
+    #[tokio::test]
+    async fn interim_responses_are_skipped() {
+        let resp = read(
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok",
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), 201);
+        assert_eq!(body(resp).await, b"ok");
+    }
+
+    #[tokio::test]
+    async fn malformed_heads_are_accepted() {
+        let resp = read(
+            b"HTTP/1.1  200  OK\r\nX-Folded: a\r\n b\r\nBad Header\r\nContent-Length : 2\r\n\r\nok",
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(body(resp).await, b"ok");
+    }
+
+    #[tokio::test]
+    async fn serialize_keeps_the_template_headers() {
+        let req = Request::post("http://example.com:8080/a?b")
+            .extension(OriginalHeaders(vec![
+                crate::request_template::Header {
+                    name: b"X-Dup".to_vec(),
+                    value: b"1".to_vec(),
+                },
+                crate::request_template::Header {
+                    name: b"x-dup".to_vec(),
+                    value: b"2".to_vec(),
+                },
+            ]))
+            .body(Body::from("body"))
+            .unwrap();
+        assert_eq!(
+            serialize(req).await.unwrap(),
+            b"POST /a?b HTTP/1.1\r\nHost: example.com:8080\r\nX-Dup: 1\r\nx-dup: 2\r\n\
+              Content-Length: 4\r\n\r\nbody"
+        );
+    }
+}